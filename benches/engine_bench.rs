@@ -1,8 +1,11 @@
-use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
-use kvs::{KvStore, KvsEngine, SledKvsEngine};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use kvs::{Durability, KvStore, KvStoreOptions, KvsEngine, SledKvsEngine};
 use rand::prelude::*;
+use std::thread;
 use tempfile::TempDir;
 
+const WRITES_PER_THREAD: usize = 64;
+
 fn write_bench(c: &mut Criterion) {
     let mut group = c.benchmark_group("write");
     group.sample_size(10);
@@ -25,7 +28,7 @@ fn write_bench(c: &mut Criterion) {
         b.iter_batched(
             || {
                 let temp_dir = TempDir::new().unwrap();
-                (SledKvsEngine::new(sled::open(&temp_dir).unwrap()), temp_dir)
+                (SledKvsEngine::new(sled::open(&temp_dir).unwrap()).unwrap(), temp_dir)
             },
             |(mut db, _temp_dir)| {
                 for i in 1..(1 << 10) {
@@ -57,10 +60,32 @@ fn get_bench(c: &mut Criterion) {
             })
         });
     }
+    for i in &[8, 12] {
+        group.bench_with_input(format!("kvs_mmap_{}", i), i, |b, i| {
+            let temp_dir = TempDir::new().unwrap();
+            // small segments and no cache, so that most gets read a mapped file
+            let options = KvStoreOptions::new()
+                .mmap_reads(true)
+                .cache_size(0)
+                .max_segment_size(4 * 1024);
+            let store = KvStore::open_with(temp_dir.path(), options).unwrap();
+            for key_i in 1..(1 << i) {
+                store
+                    .set(format!("key{}", key_i), "value".to_string())
+                    .unwrap();
+            }
+            let mut rng = SmallRng::from_seed([0; 16]);
+            b.iter(|| {
+                store
+                    .get(format!("key{}", rng.gen_range(1, 1 << i)))
+                    .unwrap();
+            })
+        });
+    }
     for i in &vec![8, 12] {
         group.bench_with_input(format!("sled_{}", i), i, |b, i| {
             let temp_dir = TempDir::new().unwrap();
-            let mut db = SledKvsEngine::new(sled::open(&temp_dir).unwrap());
+            let mut db = SledKvsEngine::new(sled::open(&temp_dir).unwrap()).unwrap();
             for key_i in 1..(1 << i) {
                 db.set(format!("key{}", key_i), "value".to_string())
                     .unwrap();
@@ -74,5 +99,52 @@ fn get_bench(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, write_bench, get_bench);
+fn concurrent_write_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("concurrent_write");
+    group.sample_size(10);
+    for &threads in &[1, 4, 16] {
+        group.throughput(Throughput::Elements((threads * WRITES_PER_THREAD) as u64));
+        group.bench_with_input(BenchmarkId::new("kvs", threads), &threads, |b, &threads| {
+            let temp_dir = TempDir::new().unwrap();
+            let options = KvStoreOptions::new().durability(Durability::Always);
+            let store = KvStore::open_with(temp_dir.path(), options).unwrap();
+            b.iter(|| concurrent_writes(&store, threads))
+        });
+        group.bench_with_input(
+            BenchmarkId::new("sled", threads),
+            &threads,
+            |b, &threads| {
+                let temp_dir = TempDir::new().unwrap();
+                let db = SledKvsEngine::with_durability(
+                    sled::open(&temp_dir).unwrap(),
+                    Durability::Always,
+                )
+                .unwrap();
+                b.iter(|| concurrent_writes(&db, threads))
+            },
+        );
+    }
+    group.finish();
+}
+
+// Every thread writes its own keys, syncing each write to disk.
+fn concurrent_writes<E: KvsEngine>(engine: &E, threads: usize) {
+    let handles: Vec<_> = (0..threads)
+        .map(|thread_id| {
+            let engine = engine.clone();
+            thread::spawn(move || {
+                for i in 0..WRITES_PER_THREAD {
+                    engine
+                        .set(format!("key{}_{}", thread_id, i), "value".to_string())
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+criterion_group!(benches, write_bench, get_bench, concurrent_write_bench);
 criterion_main!(benches);
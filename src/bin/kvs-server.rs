@@ -1,15 +1,20 @@
 use clap::arg_enum;
 use log::{error, info, warn};
 use std::env::current_dir;
-use std::fs;
+use std::fs::{self, File};
+use std::io::BufWriter;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::process::exit;
+use std::time::Duration;
 use structopt::StructOpt;
 
 use kvs::*;
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
 const DEFAULT_ENGINE: Engine = Engine::kvs;
+const DEFAULT_DURABILITY: Durability = Durability::Always;
+const ENCRYPTION_KEY_VAR: &str = "KVS_ENCRYPTION_KEY";
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "kvs-client")]
@@ -30,6 +35,138 @@ struct Opt {
         possible_values = &Engine::variants()
     )]
     engine: Option<Engine>,
+
+    #[structopt(
+        long,
+        help = "Compacts the kvs log once stale commands take more than BYTES",
+        value_name = "BYTES",
+        conflicts_with = "compaction-ratio"
+    )]
+    compaction_threshold: Option<u64>,
+
+    #[structopt(
+        long,
+        help = "Compacts the kvs log once stale commands take more than RATIO of the live data",
+        value_name = "RATIO"
+    )]
+    compaction_ratio: Option<f64>,
+
+    #[structopt(
+        long,
+        help = "Compacts the kvs log files in which stale commands take at least RATIO of the bytes",
+        value_name = "RATIO"
+    )]
+    segment_stale_ratio: Option<f64>,
+
+    #[structopt(
+        long,
+        help = "Limits the kvs compaction I/O to BYTES per second",
+        value_name = "BYTES"
+    )]
+    compaction_rate_limit: Option<u64>,
+
+    #[structopt(
+        long,
+        help = "Starts a new kvs log file once the active one reaches BYTES",
+        value_name = "BYTES"
+    )]
+    max_segment_size: Option<u64>,
+
+    #[structopt(
+        long,
+        help = "Sets the memory budget of the kvs read cache",
+        value_name = "BYTES"
+    )]
+    cache_size: Option<usize>,
+
+    #[structopt(long, help = "Reads sealed kvs log files through memory maps")]
+    mmap_reads: bool,
+
+    #[structopt(
+        long,
+        help = "Sets the buffer size of the kvs log readers",
+        value_name = "BYTES"
+    )]
+    read_buffer_size: Option<usize>,
+
+    #[structopt(
+        long,
+        help = "Sets the buffer size of the kvs log writers",
+        value_name = "BYTES"
+    )]
+    write_buffer_size: Option<usize>,
+
+    #[structopt(
+        long,
+        help = "Sets when writes are synced to disk: always (the default), never or an interval like 100ms",
+        value_name = "DURABILITY",
+        parse(try_from_str = parse_durability)
+    )]
+    durability: Option<Durability>,
+
+    #[structopt(
+        long,
+        help = "Fails instead of creating the kvs store directory if it is missing"
+    )]
+    no_create_if_missing: bool,
+
+    #[structopt(long, help = "Fails if the directory already holds a kvs store")]
+    error_if_exists: bool,
+
+    #[structopt(
+        long,
+        help = "Fails on a torn record at the end of the kvs log instead of dropping it"
+    )]
+    strict_recovery: bool,
+
+    #[structopt(
+        long,
+        help = "Encrypts the kvs log with the key in FILE, or in $KVS_ENCRYPTION_KEY if not given",
+        value_name = "FILE",
+        parse(from_os_str)
+    )]
+    key_file: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        help = "Reads kvs log files encrypted with the key in FILE, until a compaction rewrites them",
+        value_name = "FILE",
+        parse(from_os_str),
+        number_of_values = 1
+    )]
+    old_key_file: Vec<PathBuf>,
+
+    #[structopt(
+        long,
+        help = "Writes every key/value pair to FILE and exits instead of serving",
+        value_name = "FILE",
+        parse(from_os_str),
+        conflicts_with = "import"
+    )]
+    export: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        help = "Sets the key/value pairs read from FILE and exits instead of serving",
+        value_name = "FILE",
+        parse(from_os_str)
+    )]
+    import: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        help = "Sets the format of the export and import files: jsonl or csv",
+        value_name = "FORMAT",
+        default_value = "jsonl"
+    )]
+    dump_format: DumpFormat,
+
+    #[structopt(
+        long,
+        help = "Imports the key/value pairs in batches of SIZE",
+        value_name = "SIZE"
+    )]
+    import_batch_size: Option<usize>,
 }
 arg_enum! {
     #[allow(non_camel_case_types)]
@@ -65,17 +202,141 @@ fn run(opt: Opt) -> Result<()> {
     info!("Listening of address: {}", opt.addr);
     info!("Storage engine: {}", opt.engine.unwrap_or(DEFAULT_ENGINE));
 
+    if engine == Engine::sled {
+        let ignored = kvs_only_options(&opt);
+        if !ignored.is_empty() {
+            return Err(KvsError::StringError(format!(
+                "{} only apply to the kvs engine",
+                ignored.join(", ")
+            )));
+        }
+    }
+
     fs::write(current_dir()?.join("engine"), format!("{}", engine))?;
     match engine {
-        Engine::kvs => run_with_engine(KvStore::open(current_dir()?)?, opt.addr),
-        Engine::sled => run_with_engine(SledKvsEngine::new(sled::open(current_dir()?)?), opt.addr),
+        Engine::kvs => run_with_engine(
+            KvStore::open_with(current_dir()?, kvs_options(&opt)?)?,
+            &opt,
+        ),
+        Engine::sled => run_with_engine(
+            SledKvsEngine::with_durability(
+                sled::open(current_dir()?)?,
+                opt.durability.unwrap_or(DEFAULT_DURABILITY),
+            )?,
+            &opt,
+        ),
     }?;
     Ok(())
 }
 
-fn run_with_engine<E: KvsEngine>(engine: E, addr: SocketAddr) -> Result<()> {
+fn kvs_options(opt: &Opt) -> Result<KvStoreOptions> {
+    let mut options = KvStoreOptions::new()
+        .durability(opt.durability.unwrap_or(DEFAULT_DURABILITY))
+        .create_if_missing(!opt.no_create_if_missing)
+        .error_if_exists(opt.error_if_exists)
+        .mmap_reads(opt.mmap_reads);
+    if opt.strict_recovery {
+        options = options.recovery_mode(RecoveryMode::Strict);
+    }
+    if let Some(bytes) = opt.compaction_threshold {
+        options = options.compaction_trigger(CompactionTrigger::StaleBytes(bytes));
+    }
+    if let Some(ratio) = opt.compaction_ratio {
+        options = options.compaction_trigger(CompactionTrigger::StaleRatio(ratio));
+    }
+    if let Some(ratio) = opt.segment_stale_ratio {
+        options = options.segment_stale_ratio(ratio);
+    }
+    if let Some(bytes) = opt.compaction_rate_limit {
+        options = options.compaction_rate_limit(bytes);
+    }
+    if let Some(size) = opt.max_segment_size {
+        options = options.max_segment_size(size);
+    }
+    if let Some(size) = opt.cache_size {
+        options = options.cache_size(size);
+    }
+    if let Some(size) = opt.read_buffer_size {
+        options = options.read_buffer_size(size);
+    }
+    if let Some(size) = opt.write_buffer_size {
+        options = options.write_buffer_size(size);
+    }
+    if let Some(path) = &opt.key_file {
+        options = options.encryption_key(EncryptionKey::from_file(path)?);
+    } else if std::env::var_os(ENCRYPTION_KEY_VAR).is_some() {
+        options = options.encryption_key(EncryptionKey::from_env(ENCRYPTION_KEY_VAR)?);
+    }
+    for path in &opt.old_key_file {
+        options = options.old_encryption_key(EncryptionKey::from_file(path)?);
+    }
+    Ok(options)
+}
+
+// names of the kvs-only options given, which the sled engine would ignore
+fn kvs_only_options(opt: &Opt) -> Vec<&'static str> {
+    let given = [
+        ("--compaction-threshold", opt.compaction_threshold.is_some()),
+        ("--compaction-ratio", opt.compaction_ratio.is_some()),
+        ("--segment-stale-ratio", opt.segment_stale_ratio.is_some()),
+        (
+            "--compaction-rate-limit",
+            opt.compaction_rate_limit.is_some(),
+        ),
+        ("--max-segment-size", opt.max_segment_size.is_some()),
+        ("--cache-size", opt.cache_size.is_some()),
+        ("--mmap-reads", opt.mmap_reads),
+        ("--read-buffer-size", opt.read_buffer_size.is_some()),
+        ("--write-buffer-size", opt.write_buffer_size.is_some()),
+        ("--no-create-if-missing", opt.no_create_if_missing),
+        ("--error-if-exists", opt.error_if_exists),
+        ("--strict-recovery", opt.strict_recovery),
+        ("--key-file", opt.key_file.is_some()),
+        ("--old-key-file", !opt.old_key_file.is_empty()),
+        (
+            "$KVS_ENCRYPTION_KEY",
+            std::env::var_os(ENCRYPTION_KEY_VAR).is_some(),
+        ),
+    ];
+    given
+        .iter()
+        .filter(|(_, given)| *given)
+        .map(|(name, _)| *name)
+        .collect()
+}
+
+fn parse_durability(s: &str) -> std::result::Result<Durability, String> {
+    match s {
+        "always" => Ok(Durability::Always),
+        "never" => Ok(Durability::Never),
+        _ => s
+            .strip_suffix("ms")
+            .and_then(|ms| ms.parse().ok())
+            .map(|ms| Durability::Interval(Duration::from_millis(ms)))
+            .ok_or_else(|| format!("invalid durability: {}", s)),
+    }
+}
+
+fn run_with_engine<E: KvsEngine>(engine: E, opt: &Opt) -> Result<()> {
+    if let Some(path) = &opt.export {
+        let file = BufWriter::new(File::create(path)?);
+        let count = export(&engine, file, opt.dump_format)?;
+        info!("Exported {} pairs to {:?}", count, path);
+        return Ok(());
+    }
+    if let Some(path) = &opt.import {
+        let mut options = ImportOptions::new().format(opt.dump_format);
+        if let Some(size) = opt.import_batch_size {
+            options = options.batch_size(size);
+        }
+        let count = import(&engine, File::open(path)?, options, |count| {
+            info!("Imported {} pairs", count)
+        })?;
+        info!("Imported {} pairs from {:?}", count, path);
+        return Ok(());
+    }
     let mut server = KvsServer::new(engine);
-    server.run(addr)
+    server.run(opt.addr)
 }
 
 fn current_engine() -> Result<Option<Engine>> {
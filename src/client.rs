@@ -1,7 +1,9 @@
 use std::io::{BufReader, BufWriter, Write};
 use std::net::{TcpStream, ToSocketAddrs};
 
-use crate::{GetResponse, KvsError, RemoveResponse, Request, Response, Result, SetResponse};
+use crate::{
+    GetBytesResponse, GetResponse, KvsError, RemoveResponse, Request, Response, Result, SetResponse,
+};
 
 use serde::Deserialize;
 use serde_json::de::{Deserializer, IoRead};
@@ -23,31 +25,57 @@ impl KvsClient {
     }
 
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        let req = Request::Set { key, value };
+        self.set_request(Request::Set { key, value })
+    }
+
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        let req = Request::Get { key };
         serde_json::to_writer(&mut self.writer, &req)?;
         self.writer.flush()?;
         let resp = Response::deserialize(&mut self.reader)?;
         match resp {
-            Response::Set(SetResponse::Ok(_)) => Ok(()),
-            Response::Set(SetResponse::Err(e)) => Err(KvsError::StringError(e)),
+            Response::Get(GetResponse::Ok(value)) => Ok(value),
+            Response::Get(GetResponse::Err(e)) => Err(KvsError::StringError(e)),
             _ => Err(KvsError::UnexpectedCommandType),
         }
     }
 
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        let req = Request::Get { key };
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_request(Request::Remove { key })
+    }
+
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.set_request(Request::SetBytes { key, value })
+    }
+
+    pub fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let req = Request::GetBytes { key };
         serde_json::to_writer(&mut self.writer, &req)?;
         self.writer.flush()?;
         let resp = Response::deserialize(&mut self.reader)?;
         match resp {
-            Response::Get(GetResponse::Ok(value)) => Ok(value),
-            Response::Get(GetResponse::Err(e)) => Err(KvsError::StringError(e)),
+            Response::GetBytes(GetBytesResponse::Ok(value)) => Ok(value),
+            Response::GetBytes(GetBytesResponse::Err(e)) => Err(KvsError::StringError(e)),
             _ => Err(KvsError::UnexpectedCommandType),
         }
     }
 
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        let req = Request::Remove { key };
+    pub fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        self.remove_request(Request::RemoveBytes { key })
+    }
+
+    fn set_request(&mut self, req: Request) -> Result<()> {
+        serde_json::to_writer(&mut self.writer, &req)?;
+        self.writer.flush()?;
+        let resp = Response::deserialize(&mut self.reader)?;
+        match resp {
+            Response::Set(SetResponse::Ok(_)) => Ok(()),
+            Response::Set(SetResponse::Err(e)) => Err(KvsError::StringError(e)),
+            _ => Err(KvsError::UnexpectedCommandType),
+        }
+    }
+
+    fn remove_request(&mut self, req: Request) -> Result<()> {
         serde_json::to_writer(&mut self.writer, &req)?;
         self.writer.flush()?;
         let resp = Response::deserialize(&mut self.reader)?;
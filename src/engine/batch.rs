@@ -0,0 +1,61 @@
+/// Sets and removes applied atomically by `KvsEngine::write_batch`.
+///
+/// Either all the changes of the batch are applied, in order, or none of them
+/// is, even if the process crashes while the batch is written. Removing a key
+/// that does not exist does nothing.
+///
+/// ```rust
+/// # use kvs::WriteBatch;
+/// let mut batch = WriteBatch::new();
+/// batch.set("from".to_owned(), "90".to_owned());
+/// batch.set("to".to_owned(), "110".to_owned());
+/// batch.remove("pending".to_owned());
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct WriteBatch {
+    pub(crate) ops: Vec<BatchOp>,
+}
+
+/// Change recorded in a `WriteBatch`.
+#[derive(Clone, Debug)]
+pub(crate) enum BatchOp {
+    Set { key: Vec<u8>, value: Vec<u8> },
+    Remove { key: Vec<u8> },
+}
+
+impl WriteBatch {
+    /// Creates an empty batch.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the value of `key` when the batch is written.
+    pub fn set(&mut self, key: String, value: String) {
+        self.set_bytes(key.into_bytes(), value.into_bytes());
+    }
+
+    /// Removes `key` when the batch is written.
+    pub fn remove(&mut self, key: String) {
+        self.remove_bytes(key.into_bytes());
+    }
+
+    /// Sets the value of the binary `key` when the batch is written.
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.ops.push(BatchOp::Set { key, value });
+    }
+
+    /// Removes the binary `key` when the batch is written.
+    pub fn remove_bytes(&mut self, key: Vec<u8>) {
+        self.ops.push(BatchOp::Remove { key });
+    }
+
+    /// Returns the number of changes in the batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Returns `true` if the batch holds no change.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
@@ -0,0 +1,301 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::iter;
+use std::str::{self, FromStr};
+
+use csv::StringRecord;
+use serde::{Deserialize, Serialize};
+
+use super::{KvsEngine, ScanOptions, WriteBatch};
+use crate::{base64, KvsError, Result};
+
+/// Default number of pairs written by each batch of an import.
+pub const DEFAULT_IMPORT_BATCH_SIZE: usize = 1000;
+
+/// File format of the dumps written by `export` and read by `import`.
+///
+/// A pair whose key or value is not valid UTF-8 is dumped with both in base64,
+/// and its `encoding` set to `base64`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DumpFormat {
+    /// One JSON object `{"key": ..., "value": ...}` per line, with an
+    /// `"encoding"` member for the pairs in base64.
+    JsonLines,
+    /// A `key,value,encoding` header followed by one row per pair. The
+    /// encoding column is empty for the pairs in UTF-8, and may be missing
+    /// altogether from the dumps imported.
+    Csv,
+}
+
+impl FromStr for DumpFormat {
+    type Err = KvsError;
+
+    /// Parses `jsonl` or `csv`.
+    fn from_str(s: &str) -> Result<DumpFormat> {
+        match s {
+            "jsonl" => Ok(DumpFormat::JsonLines),
+            "csv" => Ok(DumpFormat::Csv),
+            _ => Err(KvsError::InvalidOption(format!(
+                "unknown dump format {}, expected jsonl or csv",
+                s
+            ))),
+        }
+    }
+}
+
+/// Options of `import`.
+///
+/// ```rust
+/// # use kvs::{DumpFormat, ImportOptions};
+/// let options = ImportOptions::new()
+///     .format(DumpFormat::Csv)
+///     .batch_size(10_000);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ImportOptions {
+    format: DumpFormat,
+    batch_size: usize,
+}
+
+impl Default for ImportOptions {
+    fn default() -> Self {
+        ImportOptions {
+            format: DumpFormat::JsonLines,
+            batch_size: DEFAULT_IMPORT_BATCH_SIZE,
+        }
+    }
+}
+
+impl ImportOptions {
+    /// Creates options reading JSON Lines in batches of
+    /// `DEFAULT_IMPORT_BATCH_SIZE` pairs.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the format of the dump.
+    pub fn format(mut self, format: DumpFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Sets the number of pairs written by each `WriteBatch`. A batch of one
+    /// pair still writes it atomically, and a batch of zero is taken as one.
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+}
+
+/// Key/value pair of a dump.
+#[derive(Deserialize, Serialize)]
+struct Pair {
+    key: String,
+    value: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encoding: Option<Encoding>,
+}
+
+/// Encoding of the key and value of a `Pair` that are not UTF-8 strings.
+#[derive(Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Encoding {
+    Base64,
+}
+
+impl Pair {
+    /// Returns the pair of `key` and `value`, in base64 unless both are valid
+    /// UTF-8.
+    fn new(key: Vec<u8>, value: Vec<u8>) -> Pair {
+        if let (Ok(key), Ok(value)) = (str::from_utf8(&key), str::from_utf8(&value)) {
+            return Pair {
+                key: key.to_owned(),
+                value: value.to_owned(),
+                encoding: None,
+            };
+        }
+        Pair {
+            key: base64::encode(&key),
+            value: base64::encode(&value),
+            encoding: Some(Encoding::Base64),
+        }
+    }
+
+    /// Returns the key and the value of the pair read at `line` of a dump.
+    fn into_bytes(self, line: u64) -> Result<(Vec<u8>, Vec<u8>)> {
+        match self.encoding {
+            None => Ok((self.key.into_bytes(), self.value.into_bytes())),
+            Some(Encoding::Base64) => {
+                match (base64::decode(&self.key), base64::decode(&self.value)) {
+                    (Some(key), Some(value)) => Ok((key, value)),
+                    _ => Err(KvsError::InvalidDump {
+                        line,
+                        reason: "invalid base64".to_owned(),
+                    }),
+                }
+            }
+        }
+    }
+}
+
+/// Writes every live key/value pair of `engine` to `writer` in key order.
+///
+/// The pairs are read by a single scan and streamed, so the dump holds the
+/// keys present when it starts and never the whole store in memory. Expiry
+/// times are not dumped: a key set with a TTL that has not expired yet is
+/// dumped like any other key.
+///
+/// Returns the number of pairs written.
+///
+/// # Errors
+///
+/// It propagates errors during the scan and writing the dump.
+pub fn export<E, W>(engine: &E, writer: W, format: DumpFormat) -> Result<u64>
+where
+    E: KvsEngine,
+    W: Write,
+{
+    let pairs = engine.scan_bytes(.., ScanOptions::new())?;
+    let mut count = 0;
+    match format {
+        DumpFormat::JsonLines => {
+            let mut writer = writer;
+            for pair in pairs {
+                let (key, value) = pair?;
+                serde_json::to_writer(&mut writer, &Pair::new(key, value))?;
+                writer.write_all(b"\n")?;
+                count += 1;
+            }
+            writer.flush()?;
+        }
+        DumpFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(writer);
+            writer
+                .write_record(["key", "value", "encoding"])
+                .map_err(csv_error)?;
+            for pair in pairs {
+                let (key, value) = pair?;
+                let pair = Pair::new(key, value);
+                let encoding = match pair.encoding {
+                    Some(Encoding::Base64) => "base64",
+                    None => "",
+                };
+                writer
+                    .write_record([&pair.key, &pair.value, encoding])
+                    .map_err(csv_error)?;
+                count += 1;
+            }
+            writer.flush()?;
+        }
+    }
+    Ok(count)
+}
+
+/// Sets every key/value pair of the dump read from `reader` in `engine`.
+///
+/// The pairs are written in batches of `ImportOptions::batch_size`, and
+/// `progress` is called with the number of pairs written so far after every
+/// batch. Keys already in the engine are overwritten, and keys missing from
+/// the dump are left alone.
+///
+/// Returns the number of pairs written.
+///
+/// # Errors
+///
+/// It returns `KvsError::InvalidDump` if the dump is malformed, in which case
+/// the batches before the malformed line have been written, and propagates
+/// errors during reading the dump and writing the batches.
+pub fn import<E, R, F>(engine: &E, reader: R, options: ImportOptions, progress: F) -> Result<u64>
+where
+    E: KvsEngine,
+    R: Read,
+    F: FnMut(u64),
+{
+    match options.format {
+        DumpFormat::JsonLines => import_pairs(engine, json_lines(reader), options, progress),
+        DumpFormat::Csv => import_pairs(engine, csv_rows(reader), options, progress),
+    }
+}
+
+/// Writes `pairs` to `engine` in batches, calling `progress` after each one.
+fn import_pairs<E, I, F>(
+    engine: &E,
+    pairs: I,
+    options: ImportOptions,
+    mut progress: F,
+) -> Result<u64>
+where
+    E: KvsEngine,
+    I: Iterator<Item = Result<(u64, Pair)>>,
+    F: FnMut(u64),
+{
+    let mut batch = WriteBatch::new();
+    let mut count = 0;
+    for pair in pairs {
+        let (line, pair) = pair?;
+        let (key, value) = pair.into_bytes(line)?;
+        batch.set_bytes(key, value);
+        if batch.len() >= options.batch_size {
+            count += batch.len() as u64;
+            engine.write_batch(std::mem::take(&mut batch))?;
+            progress(count);
+        }
+    }
+    if !batch.is_empty() {
+        count += batch.len() as u64;
+        engine.write_batch(batch)?;
+        progress(count);
+    }
+    Ok(count)
+}
+
+/// Returns the pairs of a JSON Lines dump with their line, skipping blank
+/// lines.
+fn json_lines<R: Read>(reader: R) -> impl Iterator<Item = Result<(u64, Pair)>> {
+    BufReader::new(reader)
+        .lines()
+        .enumerate()
+        .filter_map(|(line_no, line)| {
+            let line_no = line_no as u64 + 1;
+            match line {
+                Ok(line) if line.trim().is_empty() => None,
+                Ok(line) => Some(
+                    serde_json::from_str(&line)
+                        .map(|pair| (line_no, pair))
+                        .map_err(|e| KvsError::InvalidDump {
+                            line: line_no,
+                            reason: e.to_string(),
+                        }),
+                ),
+                Err(e) => Some(Err(e.into())),
+            }
+        })
+}
+
+/// Returns the pairs of a CSV dump with their line, whose first row is the
+/// header.
+fn csv_rows<R: Read>(reader: R) -> impl Iterator<Item = Result<(u64, Pair)>> {
+    let mut reader = csv::Reader::from_reader(reader);
+    let mut record = StringRecord::new();
+    iter::from_fn(move || match reader.read_record(&mut record) {
+        Ok(true) => {
+            let line = record.position().map_or(0, |position| position.line());
+            let pair = reader
+                .headers()
+                .and_then(|headers| record.deserialize(Some(headers)))
+                .map_err(csv_error);
+            Some(pair.map(|pair| (line, pair)))
+        }
+        Ok(false) => None,
+        Err(e) => Some(Err(csv_error(e))),
+    })
+}
+
+/// Converts a CSV error, keeping I/O errors as they are.
+fn csv_error(e: csv::Error) -> KvsError {
+    let line = e.position().map_or(0, |position| position.line());
+    let reason = e.to_string();
+    match e.into_kind() {
+        csv::ErrorKind::Io(e) => e.into(),
+        _ => KvsError::InvalidDump { line, reason },
+    }
+}
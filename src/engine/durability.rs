@@ -0,0 +1,72 @@
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use log::error;
+
+use crate::Result;
+
+/// When writes acknowledged by an engine are synced to disk.
+///
+/// What each mode guarantees after a process crash and after a power failure
+/// depends on the engine, see `KvStoreOptions::durability` and
+/// `SledKvsEngine`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Durability {
+    /// Sync every write to disk before acknowledging it.
+    Always,
+    /// Sync from a background thread at the given interval.
+    Interval(Duration),
+    /// Never sync explicitly and leave it to the OS or the underlying store.
+    Never,
+}
+
+/// A background thread calling a function at a fixed interval, such as a sync.
+///
+/// The thread calls the function one last time and stops when this handle is
+/// dropped.
+pub(crate) struct PeriodicTask {
+    // dropping the sender wakes the thread up and stops it
+    stop: Mutex<Option<Sender<()>>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl PeriodicTask {
+    /// Spawns a thread named `name` calling `task` every `interval`.
+    ///
+    /// Errors returned by `task` are logged and do not stop the thread.
+    pub fn spawn<F>(name: &str, interval: Duration, mut task: F) -> Result<PeriodicTask>
+    where
+        F: FnMut() -> Result<()> + Send + 'static,
+    {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let task_name = name.to_owned();
+        let thread = thread::Builder::new()
+            .name(name.to_owned())
+            .spawn(move || loop {
+                let stopping = stopped.recv_timeout(interval) != Err(RecvTimeoutError::Timeout);
+                if let Err(e) = task() {
+                    error!("{} failed: {}", task_name, e);
+                }
+                if stopping {
+                    break;
+                }
+            })?;
+        Ok(PeriodicTask {
+            stop: Mutex::new(Some(stop)),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for PeriodicTask {
+    fn drop(&mut self) {
+        drop(self.stop.lock().unwrap().take());
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("periodic task thread panicked");
+            }
+        }
+    }
+}
@@ -0,0 +1,19 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Returns the current time in milliseconds since the Unix epoch, the unit of
+/// expiry times.
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_millis() as u64)
+}
+
+/// Returns the expiry time of a key set now to live for `ttl`.
+pub(crate) fn expiry_time(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis() as u64)
+}
+
+/// Returns whether a key with the given expiry time has expired at `now`.
+pub(crate) fn is_expired(expires_at: Option<u64>, now: u64) -> bool {
+    matches!(expires_at, Some(expires_at) if expires_at <= now)
+}
@@ -1,28 +1,65 @@
-use std::cell::RefCell;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fs::{self, File, OpenOptions};
 use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::ops::Range;
+use std::ops::{Range, RangeBounds};
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::thread;
+use std::time::Duration;
 
 use crossbeam_skiplist::SkipMap;
-use log::error;
-use serde::{Deserialize, Serialize};
-use serde_json::Deserializer;
+use log::{error, warn};
+use memmap2::Mmap;
 
-use super::KvsEngine;
-use crate::{KvsError, Result};
+use super::batch::BatchOp;
+use super::durability::PeriodicTask;
+use super::expiry;
+use super::{check_keyspace_name, ByteScan, KvsEngine, ScanOptions, WriteBatch};
+use crate::{Durability, KvsError, Result};
 use std::ffi::OsStr;
 
-const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+mod cache;
+mod commit;
+mod compactor;
+mod compression;
+mod encryption;
+mod hint;
+mod keyspace;
+mod legacy;
+mod options;
+mod rate_limit;
+mod record;
+mod snapshot;
+mod transaction;
+
+pub use self::compression::Compression;
+pub use self::encryption::EncryptionKey;
+pub use self::options::{CompactionTrigger, KvStoreOptions, RecoveryMode};
+pub use self::snapshot::Snapshot;
+pub use self::transaction::KvStoreTransaction;
+
+use self::cache::ReadCache;
+use self::commit::CommitQueue;
+use self::compactor::{Compactor, CompactorHandle};
+use self::compression::Compressor;
+use self::encryption::FileCipher;
+use self::hint::HintEntry;
+use self::keyspace::{Registry, DEFAULT_KEYSPACE};
+use self::options::MIN_RATIO_COMPACTION_BYTES;
+use self::rate_limit::RateLimiter;
+use self::record::Encoder;
 
 /// The `KvStore` stores string key/value pairs.
 ///
 /// Key/value pairs are persisted to disk in log files. Log files are named after
 /// monotonically increasing generation numbers with a `log` extension name.
-/// A `BTreeMap` in memory stores the keys and the value locations for fast query.
+/// A `BTreeMap` in memory stores the keys and the value locations for fast query,
+/// and stale commands are compacted away by a background thread.
+///
+/// `KvStore` is `Sync`, so a single store can be shared through an `Arc`.
+/// Clones are cheap too: all of them share one file handle per log file.
 ///
 /// ```rust
 /// # use kvs::{KvStore, Result};
@@ -42,171 +79,730 @@ pub struct KvStore {
     path: Arc<PathBuf>,
     reader: KvStoreReader,
     writer: Arc<Mutex<KvStoreWriter>>,
-    index: Arc<SkipMap<String, CommandPos>>,
+    // batches the commands of concurrent writers
+    commits: Arc<CommitQueue<Update>>,
+    // id and index of the keyspace this handle reads and writes
+    keyspace: u32,
+    index: Arc<Index>,
+    // compresses the values written, shared with the compactor
+    compressor: Arc<Compressor>,
+    // appends removes for the expired keys; dropped before the compactor, so
+    // that it never asks for a compaction once the compactor has shut down
+    _reaper: Arc<PeriodicTask>,
+    // stops the compactor thread when the last clone is dropped
+    compactor: Arc<CompactorHandle>,
+    // syncs the active log file in `Durability::Interval` mode
+    _syncer: Option<Arc<PeriodicTask>>,
 }
 
+/// Reads records with positional reads, so that all clones of a `KvStore` and
+/// all threads share a single handle per log file.
+#[derive(Clone)]
 struct KvStoreReader {
     path: Arc<PathBuf>,
-    // generation of the latest compaction file
-    safe_point: Arc<AtomicU64>,
-    readers: RefCell<BTreeMap<u64, BufReaderWithPos<File>>>,
+    options: Arc<KvStoreOptions>,
+    files: Arc<RwLock<OpenFiles>>,
+    // generation the writer appends to, which is never mapped
+    active_gen: Arc<AtomicU64>,
+    // values read by all clones of the store
+    cache: Arc<ReadCache>,
+    // keys the log files may be encrypted with
+    keys: Arc<Vec<EncryptionKey>>,
+}
+
+/// Log files opened by the readers.
+#[derive(Default)]
+struct OpenFiles {
+    files: BTreeMap<u64, Arc<File>>,
+    // memory maps of sealed log files if `KvStoreOptions::mmap_reads` is on
+    maps: BTreeMap<u64, Arc<Mmap>>,
+    // generations deleted by compactions, which are not kept open again
+    removed: BTreeSet<u64>,
+    // ciphers of the log files, `None` for files written in the clear
+    ciphers: BTreeMap<u64, Option<Arc<FileCipher>>>,
+}
+
+/// Statistics of a `KvStore`, returned by `KvStore::stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KvStoreStats {
+    /// Number of gets answered from the read cache.
+    pub cache_hits: u64,
+    /// Number of gets that had to read the log.
+    pub cache_misses: u64,
+    /// Bytes of the values written since the store was opened, by writes and
+    /// compactions, before compression.
+    pub value_bytes: u64,
+    /// Bytes the same values take in the log after compression.
+    pub stored_value_bytes: u64,
+}
+
+impl KvStoreStats {
+    /// Returns how many times smaller the values written since the store was
+    /// opened are in the log, `1.0` if nothing was written.
+    pub fn compression_ratio(&self) -> f64 {
+        if self.stored_value_bytes == 0 {
+            return 1.0;
+        }
+        self.value_bytes as f64 / self.stored_value_bytes as f64
+    }
 }
 
 struct KvStoreWriter {
-    reader: KvStoreReader,
     writer: BufWriterWithPos<File>,
     current_gen: u64,
+    // `current_gen` shared with the readers
+    active_gen: Arc<AtomicU64>,
     // the number of bytes representing "stale" commands that could be
     // deleted during a compaction
     uncompacted: u64,
+    // size and stale bytes of every generation
+    segments: BTreeMap<u64, Segment>,
+    // the number of bytes of the commands the index points to
+    live: u64,
+    // expiry times of the keys set with a time to live, which may have been
+    // set again or removed since
+    expiries: BTreeSet<(u64, u32, Vec<u8>)>,
+    // sequence number of the last write
+    seq: u64,
+    // live snapshots, by sequence number
+    snapshots: Snapshots,
+    // keyspaces and keys whose index slot holds older versions or a removal
+    // for snapshots
+    versioned: BTreeSet<(u32, Vec<u8>)>,
     path: Arc<PathBuf>,
-    index: Arc<SkipMap<String, CommandPos>>,
+    options: Arc<KvStoreOptions>,
+    indexes: Arc<Indexes>,
+    // names and ids of the named keyspaces
+    registry: Registry,
+    compressor: Arc<Compressor>,
+    // cipher of the active log file if it is encrypted
+    cipher: Option<Arc<FileCipher>>,
+    compactor: Sender<compactor::Message>,
+    // whether a compaction handed to the compactor thread is still running
+    compacting: Arc<AtomicBool>,
 }
 
 impl KvStoreReader {
-    /// Close file handles with generation number less than safe_point.
-    ///
-    /// `safe_point` is updated to the latest compaction gen after a compaction finishes.
-    /// The compaction generation contains the sum of all operations before it and the
-    /// in-memory index contains no entries with generation number less than safe_point.
-    /// So we can safely close those file handles and the stale files can be deleted.
-    fn close_stale_handles(&self) {
-        let mut readers = self.readers.borrow_mut();
-        while !readers.is_empty() {
-            let first_gen = *readers.keys().next().unwrap();
-            if self.safe_point.load(Ordering::SeqCst) <= first_gen {
-                break;
-            }
-            readers.remove(&first_gen);
+    /// Close the handles of generations deleted by a compaction.
+    ///
+    /// A compaction swaps the index entries of the generations it compacted to
+    /// the new generation before deleting them, so only the entries of expired
+    /// keys, which are never read, point to a removed generation and the handles
+    /// can be closed safely. On Unix, the
+    /// files only go away once all the handles are closed.
+    fn close(&self, gens: &[u64]) {
+        let mut open = self.files.write().unwrap();
+        for gen in gens {
+            open.files.remove(gen);
+            open.maps.remove(gen);
+            open.ciphers.remove(gen);
+            open.removed.insert(*gen);
         }
     }
 
-    /// Read the log file at the given `CommandPos`.
-    fn read_and<F, R>(&self, cmd_pos: CommandPos, f: F) -> Result<R>
-    where
-        F: FnOnce(io::Take<&mut BufReaderWithPos<File>>) -> Result<R>,
-    {
-        self.close_stale_handles();
+    /// Returns the handle of the log file of generation `gen`, opening it if no
+    /// reader has opened it yet.
+    fn file(&self, gen: u64) -> Result<Arc<File>> {
+        if let Some(file) = self.files.read().unwrap().files.get(&gen) {
+            return Ok(Arc::clone(file));
+        }
+        let file = Arc::new(File::open(log_path(&self.path, gen))?);
+        let mut open = self.files.write().unwrap();
+        if open.removed.contains(&gen) {
+            // deleted while we were opening it, so only this read uses it
+            return Ok(file);
+        }
+        Ok(Arc::clone(open.files.entry(gen).or_insert(file)))
+    }
 
-        let mut readers = self.readers.borrow_mut();
-        // Open the file if we haven't opened it in this `KvStoreReader`.
-        // We don't use entry API here because we want the errors to be propogated.
-        if !readers.contains_key(&cmd_pos.gen) {
-            let reader = BufReaderWithPos::new(File::open(log_path(&self.path, cmd_pos.gen))?)?;
-            readers.insert(cmd_pos.gen, reader);
+    /// Returns the memory map of the sealed log file of generation `gen`,
+    /// mapping it if no reader has mapped it yet.
+    fn map(&self, gen: u64) -> Result<Arc<Mmap>> {
+        if let Some(map) = self.files.read().unwrap().maps.get(&gen) {
+            return Ok(Arc::clone(map));
+        }
+        // SAFETY: a sealed log file is never written to or truncated while
+        // the store is open. A compaction only deletes it, which leaves the
+        // mapping valid until it is dropped.
+        let map = Arc::new(unsafe { Mmap::map(&*self.file(gen)?)? });
+        let mut open = self.files.write().unwrap();
+        if open.removed.contains(&gen) {
+            return Ok(map);
+        }
+        Ok(Arc::clone(open.maps.entry(gen).or_insert(map)))
+    }
+
+    /// Returns the cipher of the log file of generation `gen`, reading the
+    /// header of `file` if no reader has read it yet.
+    fn cipher(&self, gen: u64, file: &File) -> Result<Option<Arc<FileCipher>>> {
+        if let Some(cipher) = self.files.read().unwrap().ciphers.get(&gen) {
+            return Ok(cipher.clone());
+        }
+        let mut reader = FileReader { file, pos: 0 };
+        let cipher = match record::read_file_header(&mut reader, gen, &self.keys)? {
+            Some(header) => header.cipher,
+            None => None,
+        };
+        let mut open = self.files.write().unwrap();
+        if !open.removed.contains(&gen) {
+            open.ciphers.insert(gen, cipher.clone());
         }
-        let reader = readers.get_mut(&cmd_pos.gen).unwrap();
-        reader.seek(SeekFrom::Start(cmd_pos.pos))?;
-        let cmd_reader = reader.take(cmd_pos.len);
-        f(cmd_reader)
+        Ok(cipher)
     }
 
-    // Read the log file at the given `CommandPos` and deserialize it to `Command`.
+    // Read the record at the given `CommandPos` and decode it to `Command`.
     fn read_command(&self, cmd_pos: CommandPos) -> Result<Command> {
-        self.read_and(cmd_pos, |cmd_reader| {
-            Ok(serde_json::from_reader(cmd_reader)?)
+        let corruption = KvsError::Corruption {
+            gen: cmd_pos.gen,
+            pos: cmd_pos.pos,
+        };
+        let file = self.file(cmd_pos.gen)?;
+        let cipher = self.cipher(cmd_pos.gen, &file)?;
+        if self.options.mmap_reads && cmd_pos.gen < self.active_gen.load(Ordering::SeqCst) {
+            let start = cmd_pos.pos as usize;
+            return self
+                .map(cmd_pos.gen)?
+                .get(start..start + cmd_pos.len as usize)
+                .and_then(|buf| record::decode(buf, cmd_pos.gen, cmd_pos.pos, cipher.as_deref()))
+                .ok_or(corruption);
+        }
+        read_record(&file, cmd_pos, cipher.as_deref())
+    }
+
+    /// Opens the log files of the given generations, so that the caller can
+    /// keep reading them after a compaction deletes them.
+    ///
+    /// Returns `None` if a compaction already deleted one of them.
+    fn pin<I>(&self, gens: I) -> Result<Option<BTreeMap<u64, Arc<File>>>>
+    where
+        I: IntoIterator<Item = u64>,
+    {
+        let mut files = BTreeMap::new();
+        for gen in gens {
+            if files.contains_key(&gen) {
+                continue;
+            }
+            match self.file(gen) {
+                Ok(file) => {
+                    files.insert(gen, file);
+                }
+                Err(KvsError::IO(e))
+                    if e.kind() == io::ErrorKind::NotFound
+                        && self.files.read().unwrap().removed.contains(&gen) =>
+                {
+                    return Ok(None)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(Some(files))
+    }
+
+    /// Read the value of the set command at the given `CommandPos`, going
+    /// through the read cache.
+    fn read_value(&self, cmd_pos: CommandPos) -> Result<Vec<u8>> {
+        self.read_value_with(cmd_pos, || self.read_command(cmd_pos))
+    }
+
+    /// Read the value of the set command at the given `CommandPos` from a
+    /// file returned by `pin`, going through the read cache.
+    fn read_pinned_value(&self, file: &File, cmd_pos: CommandPos) -> Result<Vec<u8>> {
+        self.read_value_with(cmd_pos, || {
+            let cipher = self.cipher(cmd_pos.gen, file)?;
+            read_record(file, cmd_pos, cipher.as_deref())
         })
     }
-}
 
-impl Clone for KvStoreReader {
-    fn clone(&self) -> Self {
-        KvStoreReader {
-            path: Arc::clone(&self.path),
-            safe_point: Arc::clone(&self.safe_point),
-            readers: RefCell::new(BTreeMap::new()),
+    fn read_value_with<F>(&self, cmd_pos: CommandPos, read: F) -> Result<Vec<u8>>
+    where
+        F: FnOnce() -> Result<Command>,
+    {
+        if let Some(value) = self.cache.get(&cmd_pos) {
+            return Ok(value);
+        }
+        match read()? {
+            Command::Set { value, .. } => {
+                self.cache.insert(cmd_pos, value.clone());
+                Ok(value)
+            }
+            Command::Remove { .. } => Err(KvsError::UnexpectedCommandType),
         }
     }
 }
 
 impl KvStoreWriter {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Command::set(key, value);
-        let pos = self.writer.pos;
-        serde_json::to_writer(&mut self.writer, &cmd);
-        self.writer.flush()?;
-        if let Command::Set { key, .. } = cmd {
-            if let Some(old_cmd) = self.index.get(&key) {
-                self.uncompacted += old_cmd.value().len;
+    /// Appends the updates of a group of writers to the log with a single
+    /// flush, and a single sync in `Durability::Always` mode.
+    ///
+    /// Returns the result of every update, in order. A remove of a key that
+    /// does not exist fails on its own, while an I/O error fails the whole group.
+    fn write_group(&mut self, updates: Vec<Update>) -> Vec<Result<()>> {
+        // whether the keys touched by the group exist after the commands so far
+        let mut exists = HashMap::new();
+        let mut results = Vec::with_capacity(updates.len());
+        let mut written = Vec::with_capacity(updates.len());
+        // headers of the batch records, which no command points to
+        let mut batch_headers = Vec::new();
+        let mut buf = Vec::new();
+        let start = self.writer.pos;
+        for update in updates {
+            let pos = start + buf.len() as u64;
+            match update {
+                Update::Command(cmd) => {
+                    let index = match self.index(cmd.keyspace()) {
+                        Some(index) => index,
+                        None => {
+                            results.push(Err(KvsError::KeyspaceDropped));
+                            continue;
+                        }
+                    };
+                    if !key_exists(&index, &exists, &cmd) {
+                        if let Command::Remove { key, .. } = cmd {
+                            results.push(Err(KvsError::KeyNotFound(
+                                String::from_utf8_lossy(&key).into_owned(),
+                            )));
+                            continue;
+                        }
+                    }
+                    exists.insert(
+                        (cmd.keyspace(), cmd.key().to_vec()),
+                        matches!(cmd, Command::Set { .. }),
+                    );
+                    self.seq += 1;
+                    buf.extend_from_slice(&record::encode(&cmd, self.seq, pos, self.encoder()));
+                    written.push((self.seq, cmd, pos..start + buf.len() as u64));
+                }
+                Update::Batch(cmds) => {
+                    // a batch touching a dropped keyspace fails as a whole
+                    let indexes: Option<Vec<_>> =
+                        cmds.iter().map(|cmd| self.index(cmd.keyspace())).collect();
+                    let indexes = match indexes {
+                        Some(indexes) => indexes,
+                        None => {
+                            results.push(Err(KvsError::KeyspaceDropped));
+                            continue;
+                        }
+                    };
+                    // removes of keys that do not exist are dropped
+                    let cmds: Vec<_> = cmds
+                        .into_iter()
+                        .zip(indexes)
+                        .filter(|(cmd, index)| {
+                            let set = matches!(cmd, Command::Set { .. });
+                            let found = key_exists(index, &exists, cmd);
+                            exists.insert((cmd.keyspace(), cmd.key().to_vec()), set);
+                            set || found
+                        })
+                        .map(|(cmd, _)| cmd)
+                        .collect();
+                    if !cmds.is_empty() {
+                        self.seq += 1;
+                        let (batch, ranges) =
+                            record::encode_batch(&cmds, self.seq, pos, self.encoder());
+                        buf.extend_from_slice(&batch);
+                        batch_headers.push(pos..pos + ranges[0].start);
+                        for (cmd, range) in cmds.into_iter().zip(ranges) {
+                            written.push((self.seq, cmd, pos + range.start..pos + range.end));
+                        }
+                    }
+                }
+            }
+            results.push(Ok(()));
+        }
+        if written.is_empty() {
+            return results;
+        }
+
+        if let Err(e) = self.append(&buf, written, batch_headers) {
+            for result in results.iter_mut().filter(|result| result.is_ok()) {
+                *result = Err(copy_error(&e));
             }
-            self.index
-                .insert(key, (self.current_gen, pos..self.writer.pos).into());
         }
+        results
+    }
 
-        if self.uncompacted > COMPACTION_THRESHOLD {
-            self.compact()?;
+    /// Appends encoded records to the active log file and updates the index
+    /// with the commands they hold, each with its sequence number and the
+    /// range of the file it was written at.
+    fn append(
+        &mut self,
+        buf: &[u8],
+        written: Vec<(u64, Command, Range<u64>)>,
+        batch_headers: Vec<Range<u64>>,
+    ) -> Result<()> {
+        let start = self.writer.pos;
+        let result = self
+            .writer
+            .write_all(buf)
+            .map_err(KvsError::from)
+            .and_then(|()| self.flush());
+        if let Err(e) = result {
+            // The part of the records still buffered would be flushed by the
+            // next write, bringing back commands reported as failed, and the
+            // part already in the file would be read on the next open.
+            self.writer.discard_from(start)?;
+            return Err(e);
+        }
+        for (seq, cmd, range) in written {
+            self.apply(seq, cmd, range);
         }
+        // a compaction copies the commands of a batch as single records
+        for range in batch_headers {
+            self.add_stale(&(self.current_gen, range).into());
+        }
+        self.maybe_compact()?;
+        self.maybe_roll()
+    }
 
-        Ok(())
+    /// Appends a remove of every key whose time to live has passed, so that the
+    /// value is not kept in the log forever and the key stays removed even if
+    /// the clock goes back.
+    fn reap_expired(&mut self) -> Result<()> {
+        let now = expiry::now_millis();
+        let mut buf = Vec::new();
+        let mut written = Vec::new();
+        let start = self.writer.pos;
+        while let Some((expires_at, keyspace, key)) = self.expiries.first().cloned() {
+            if expires_at > now {
+                break;
+            }
+            self.expiries.remove(&(expires_at, keyspace, key.clone()));
+            // skip keys set or removed again since, and dropped keyspaces
+            let current = self
+                .index(keyspace)
+                .and_then(|index| index.get(&key)?.value().expires_at());
+            if current != Some(expires_at) {
+                continue;
+            }
+            let cmd = Command::remove(keyspace, key);
+            let pos = start + buf.len() as u64;
+            self.seq += 1;
+            buf.extend_from_slice(&record::encode(&cmd, self.seq, pos, self.encoder()));
+            written.push((self.seq, cmd, pos..start + buf.len() as u64));
+        }
+        if written.is_empty() {
+            return Ok(());
+        }
+        self.append(&buf, written, Vec::new())
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        if self.index.contains_key(&key) {
-            let cmd = Command::remove(key);
-            let pos = self.writer.pos;
-            serde_json::to_writer(&mut self.writer, &cmd)?;
-            self.writer.flush()?;
-            if let Command::Remove { key } = cmd {
-                let old_cmd = self.index.remove(&key).expect("key not found");
-                self.uncompacted += old_cmd.value().len;
-                // the "remove" command itself can be deleted in the next compaction
-                // so we add its length to `uncompacted`
-                self.uncompacted += self.writer.pos - pos;
+    /// Updates the index for a command with sequence number `seq` written at
+    /// the given range of the active log file.
+    fn apply(&mut self, seq: u64, cmd: Command, range: Range<u64>) {
+        let len = range.end - range.start;
+        match cmd {
+            Command::Set {
+                keyspace,
+                key,
+                expires_at,
+                ..
+            } => {
+                self.live += len;
+                if let Some(expires_at) = expires_at {
+                    self.expiries.insert((expires_at, keyspace, key.clone()));
+                }
+                let cmd_pos = (self.current_gen, range).into();
+                let version = Version::set(seq, cmd_pos, expires_at);
+                if let Some(old_cmd) = self.index_update(keyspace, key, version) {
+                    self.add_stale(&old_cmd);
+                    self.live -= old_cmd.len;
+                }
             }
+            Command::Remove { keyspace, key } => {
+                if let Some(old_cmd) = self.index_update(keyspace, key, Version::removed(seq)) {
+                    self.add_stale(&old_cmd);
+                    self.live -= old_cmd.len;
+                }
+                // the "remove" command itself can be deleted in a compaction
+                // once no older generation holds the key, so we count it as stale
+                self.add_stale(&(self.current_gen, range).into());
+            }
+        }
+    }
+
+    /// Records a new version of `key` in the index of its keyspace, returning
+    /// the position the key pointed to before.
+    ///
+    /// The keyspace exists, since `write_group` checks it under the writer
+    /// lock.
+    fn index_update(
+        &mut self,
+        keyspace: u32,
+        key: Vec<u8>,
+        version: Version,
+    ) -> Option<CommandPos> {
+        let index = self.index(keyspace)?;
+        let (old_pos, versioned) = index_update(&index, &key, version, &self.snapshots);
+        if versioned {
+            self.versioned.insert((keyspace, key));
+        }
+        old_pos
+    }
+
+    /// Returns the index of the keyspace with id `keyspace`, or `None` if the
+    /// keyspace was dropped.
+    fn index(&self, keyspace: u32) -> Option<Arc<Index>> {
+        keyspace_index(&self.indexes, keyspace)
+    }
+
+    /// Adds the keyspace `name` to the registry and gives it an empty index.
+    ///
+    /// Returns the id and the index of the keyspace.
+    fn create_keyspace(&mut self, name: &str) -> Result<(u32, Arc<Index>)> {
+        if self.registry.get(name).is_some() {
+            return Err(KvsError::KeyspaceExists(name.to_owned()));
+        }
+        let mut registry = self.registry.clone();
+        let keyspace = registry.create(name);
+        registry.save(&self.path)?;
+        self.registry = registry;
+        let index = Arc::new(SkipMap::new());
+        self.indexes.insert(keyspace, Arc::clone(&index));
+        Ok((keyspace, index))
+    }
 
-            if self.uncompacted > COMPACTION_THRESHOLD {
-                self.compact()?;
+    /// Removes the keyspace `name` from the registry and counts all its
+    /// records as stale.
+    fn drop_keyspace(&mut self, name: &str) -> Result<()> {
+        let mut registry = self.registry.clone();
+        let keyspace = registry
+            .remove(name)
+            .ok_or_else(|| KvsError::KeyspaceNotFound(name.to_owned()))?;
+        registry.save(&self.path)?;
+        self.registry = registry;
+        let index = match self.indexes.remove(&keyspace) {
+            Some(entry) => Arc::clone(entry.value()),
+            None => return Ok(()),
+        };
+        for entry in index.iter() {
+            if let Some(cmd_pos) = entry.value().pos() {
+                self.add_stale(&cmd_pos);
+                self.live -= cmd_pos.len;
             }
-            Ok(())
-        } else {
-            Err(KvsError::KeyNotFound(key))
         }
+        // the handles and snapshots of the keyspace now read it empty
+        index.clear();
+        self.maybe_compact()
     }
 
-    fn compact(&mut self) -> Result<()> {
-        // increase current gen by 2. current_gen + 1 is for the compaction file
-        let compaction_gen = self.current_gen + 1;
-        self.current_gen += 2;
-        self.writer = new_log_file(&self.path, self.current_gen)?;
-
-        let mut compaction_writer = new_log_file(&self.path, compaction_gen)?;
-
-        let mut new_pos = 0; // pos in the new log file
-        for entry in self.index.iter() {
-            let len = self.reader.read_and(*entry.value(), |mut entry_reader| {
-                Ok(io::copy(&mut entry_reader, &mut compaction_writer)?)
-            })?;
-            self.index.insert(
-                entry.key().clone(),
-                (compaction_gen, new_pos..new_pos + len).into(),
-            );
-            new_pos += len;
+    /// Registers a snapshot at the sequence number of the last write.
+    ///
+    /// Returns the sequence number of the snapshot.
+    fn take_snapshot(&mut self) -> u64 {
+        let gen = self.current_gen;
+        self.snapshots
+            .entry(self.seq)
+            .or_insert(SnapshotRefs { gen, count: 0 })
+            .count += 1;
+        self.seq
+    }
+
+    /// Unregisters a snapshot taken at sequence number `seq` and drops the
+    /// versions of the index no other snapshot reads.
+    fn release_snapshot(&mut self, seq: u64) {
+        if let Some(refs) = self.snapshots.get_mut(&seq) {
+            refs.count -= 1;
+            if refs.count == 0 {
+                self.snapshots.remove(&seq);
+            }
+        }
+        for (keyspace, key) in std::mem::take(&mut self.versioned) {
+            let index = match self.index(keyspace) {
+                Some(index) => index,
+                None => continue,
+            };
+            let slot = match index.get(&key) {
+                Some(slot) => slot,
+                None => continue,
+            };
+            match slot.value().prune(&self.snapshots) {
+                History::Latest => {}
+                History::Versions => {
+                    self.versioned.insert((keyspace, key));
+                }
+                History::Removed => {
+                    index.remove(&key);
+                }
+            }
         }
-        compaction_writer.flush()?;
+    }
 
-        self.reader
-            .safe_point
-            .store(compaction_gen, Ordering::SeqCst);
-        self.reader.close_stale_handles();
+    /// Counts the command at `cmd_pos` as stale.
+    fn add_stale(&mut self, cmd_pos: &CommandPos) {
+        self.uncompacted += count_stale(&mut self.segments, cmd_pos);
+    }
 
-        // remove stale log files
-        // Note that actually these files are not deleted immediately because `KvStoreReader`s
-        // still keep open file handles. When `KvStoreReader` is used next time, it will clear
-        // its stale file handles. On Unix, the files will be deleted after all the handles
-        // are closed. On Windows, the deletions below will fail and stale files are expected
-        // to be deleted in the next compaction.
+    /// Replaces the generations removed by a compaction with the generation
+    /// they were compacted into, if any.
+    fn replace_segments(&mut self, victims: &[u64], compacted: Option<(u64, Segment)>) {
+        for victim in victims {
+            if let Some(removed) = self.segments.remove(victim) {
+                self.uncompacted -= removed.stale;
+            }
+        }
+        if let Some((gen, segment)) = compacted {
+            self.uncompacted += segment.stale;
+            self.segments.insert(gen, segment);
+        }
+    }
 
-        let stale_gens = sorted_gen_list(&self.path)?
-            .into_iter()
-            .filter(|&gen| gen < compaction_gen);
-        for stale_gen in stale_gens {
-            let file_path = log_path(&self.path, stale_gen);
-            if let Err(e) = fs::remove_file(&file_path) {
-                error!("{:?} cannot be deleted: {}", file_path, e);
+    /// Flushes the written commands to the OS, and syncs them to disk in
+    /// `Durability::Always` mode.
+    fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        if self.options.durability == Durability::Always {
+            self.writer.writer.get_ref().sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Flushes and syncs the active log file to disk.
+    fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    /// Moves on to a new active log file with the given generation number.
+    fn roll(&mut self, gen: u64) -> Result<()> {
+        // Only the newest generation may end with a torn record when the store
+        // is opened, so the outgoing one is synced in every mode. The periodic
+        // sync only covers the active log file anyway.
+        self.sync()?;
+        let (writer, cipher) = new_log_file(&self.path, gen, &self.options)?;
+        if self.options.durability == Durability::Always {
+            sync_dir(&self.path)?;
+        }
+        self.segments.entry(self.current_gen).or_default().size = self.writer.pos;
+        self.segments.insert(gen, Segment::default());
+        self.writer = writer;
+        self.cipher = cipher;
+        self.current_gen = gen;
+        self.active_gen.store(gen, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Returns how the records appended to the active log file are encoded.
+    fn encoder(&self) -> Encoder<'_> {
+        Encoder {
+            compressor: &self.compressor,
+            cipher: self.cipher.as_deref(),
+            gen: self.current_gen,
+        }
+    }
+
+    /// Moves on to a new generation once the active log file has reached the
+    /// maximum segment size.
+    fn maybe_roll(&mut self) -> Result<()> {
+        match self.options.max_segment_size {
+            Some(size) if self.writer.pos >= size => self.roll(self.current_gen + 1),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the stale commands call for a compaction.
+    fn needs_compaction(&self) -> bool {
+        match self.options.compaction_trigger {
+            CompactionTrigger::StaleBytes(bytes) => self.uncompacted > bytes,
+            CompactionTrigger::StaleRatio(ratio) => {
+                self.uncompacted > MIN_RATIO_COMPACTION_BYTES
+                    && self.uncompacted as f64 > ratio * self.live as f64
             }
         }
-        self.uncompacted = 0;
+    }
+
+    /// Returns the generations whose fraction of stale bytes reaches
+    /// `KvStoreOptions::segment_stale_ratio`.
+    ///
+    /// The generations that were already written to when the oldest live
+    /// snapshot was taken are kept, since the snapshot may read the stale
+    /// records of any of them.
+    fn victims(&self) -> Vec<u64> {
+        let ratio = self.options.segment_stale_ratio;
+        self.unpinned_segments()
+            .filter(|&(&gen, segment)| {
+                let size = if gen == self.current_gen {
+                    self.writer.pos
+                } else {
+                    segment.size
+                };
+                segment.stale > 0 && segment.stale as f64 >= ratio * size as f64
+            })
+            .map(|(&gen, _)| gen)
+            .collect()
+    }
+
+    /// Returns the generations no live snapshot may read, which are the ones
+    /// written to after the oldest snapshot was taken.
+    fn unpinned_segments(&self) -> impl Iterator<Item = (&u64, &Segment)> {
+        let pinned = self.snapshots.values().map(|refs| refs.gen).min();
+        self.segments
+            .iter()
+            .filter(move |&(&gen, _)| !matches!(pinned, Some(pinned) if gen <= pinned))
+    }
+
+    /// Hands the generations with the most stale commands over to the compactor
+    /// thread once there are enough stale commands and no compaction is running
+    /// yet.
+    ///
+    /// The writer moves on to a new generation right away, so writes are never
+    /// blocked by the compaction itself.
+    fn maybe_compact(&mut self) -> Result<()> {
+        if !self.needs_compaction() || self.compacting.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        let victims = self.victims();
+        if victims.is_empty() {
+            return Ok(());
+        }
+        self.start_compaction(victims, None)
+    }
+
+    /// Hands every generation no snapshot reads over to the compactor thread,
+    /// so that all their live records are rewritten with the current options.
+    ///
+    /// Returns the receiver of the result of the compaction, or `None` if there
+    /// is nothing to compact.
+    fn compact_all(&mut self) -> Result<Option<Receiver<Result<()>>>> {
+        let victims: Vec<u64> = self.unpinned_segments().map(|(&gen, _)| gen).collect();
+        if victims.is_empty() {
+            return Ok(None);
+        }
+        let (done, receiver) = mpsc::channel();
+        self.start_compaction(victims, Some(done))?;
+        Ok(Some(receiver))
+    }
+
+    /// Moves on to a new generation and hands `victims` over to the compactor
+    /// thread, which sends the result to `done` if any.
+    fn start_compaction(
+        &mut self,
+        victims: Vec<u64>,
+        done: Option<Sender<Result<()>>>,
+    ) -> Result<()> {
+        // Increase current gen by 2. current_gen + 1 is for the compaction file,
+        // which must sort after the victims and before every write made while
+        // the compaction runs.
+        let compaction_gen = self.current_gen + 1;
+        self.roll(self.current_gen + 2)?;
 
+        self.compacting.store(true, Ordering::SeqCst);
+        if self
+            .compactor
+            .send(compactor::Message::Compact {
+                gen: compaction_gen,
+                victims,
+                done,
+            })
+            .is_err()
+        {
+            // nobody else clears the flag
+            self.compacting.store(false, Ordering::SeqCst);
+            error!(
+                "compactor thread is gone, generation {} is skipped",
+                compaction_gen
+            );
+        }
         Ok(())
     }
 }
@@ -216,78 +812,474 @@ impl KvStore {
     ///
     /// This will create a new directory if the given one does not exist.
     ///
+    /// The log files written by a compaction come with a hint file listing the
+    /// location of every key, so reopening a compacted store does not need to
+    /// read the values.
+    ///
+    /// A store written by the first release, whose log files hold JSON
+    /// commands, is upgraded in place: its live pairs are rewritten as binary
+    /// records and the JSON log files deleted.
+    ///
     /// # Errors
     ///
-    /// It propagates I/O or deserialization errors during the log replay.
+    /// It propagates I/O errors during the log replay, and returns
+    /// `KvsError::Corruption` if a record fails its checksum.
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with(path, KvStoreOptions::default())
+    }
+
+    /// Opens a `KvStore` with the given path and options.
+    ///
+    /// # Errors
+    ///
+    /// Same as `KvStore::open`. It also returns `KvsError::InvalidOption` if the
+    /// options are inconsistent, an I/O error if the directory does not exist
+    /// and `create_if_missing` is off or if it already holds a store and
+    /// `error_if_exists` is on, and `KvsError::IncompleteRecord` if the newest
+    /// log ends with a torn write in `RecoveryMode::Strict`.
+    pub fn open_with(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
+        options.validate()?;
+        let options = Arc::new(options);
         let path = Arc::new(path.into());
-        fs::create_dir_all(&*path)?;
+        if !path.is_dir() {
+            if !options.create_if_missing {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{:?} does not exist", path),
+                )
+                .into());
+            }
+            fs::create_dir_all(&*path)?;
+        }
+        compactor::remove_interrupted(&path)?;
 
-        let mut readers = BTreeMap::new();
-        let index = Arc::new(SkipMap::new());
+        let mut files = BTreeMap::new();
+        let registry = Registry::load(&path)?;
+        let indexes: Arc<Indexes> = Arc::new(SkipMap::new());
+        for keyspace in std::iter::once(DEFAULT_KEYSPACE).chain(registry.ids()) {
+            indexes.insert(keyspace, Arc::new(SkipMap::new()));
+        }
 
+        let legacy_gens = legacy::set_aside(&path)?;
         let gen_list = sorted_gen_list(&path)?;
-        let mut uncompacted = 0;
+        if options.error_if_exists && !(gen_list.is_empty() && legacy_gens.is_empty()) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{:?} already holds a store", path),
+            )
+            .into());
+        }
+        let mut segments = BTreeMap::new();
+        let mut seq = 0;
+        let keys = Arc::new(options.decryption_keys());
 
         for &gen in &gen_list {
-            let mut reader = BufReaderWithPos::new(File::open(log_path(&path, gen))?)?;
-            uncompacted += load(gen, &mut reader, &*index)?;
-            readers.insert(gen, reader);
+            let mut reader = BufReaderWithPos::with_capacity(
+                options.read_buffer_size,
+                File::open(log_path(&path, gen))?,
+            )?;
+            segments.insert(gen, Segment::default());
+            let hint = match record::read_file_header(&mut reader, gen, &keys) {
+                Ok(Some(header)) => hint::read_hint(&path, gen, header.cipher.as_deref())?,
+                // a torn header is dealt with by `load`
+                Ok(None) | Err(KvsError::IncompleteRecord { .. }) => None,
+                Err(e) => return Err(e),
+            };
+            if let Some(entries) = hint {
+                seq = seq.max(load_hint(gen, entries, &indexes, &mut segments));
+                let size = reader.reader.get_ref().metadata()?.len();
+                segments.get_mut(&gen).unwrap().size = size;
+                files.insert(gen, Arc::new(reader.reader.into_inner()));
+                continue;
+            }
+            // Only the newest generation can have been cut off by a crash. A torn
+            // record anywhere else means the log was damaged after it was written.
+            let truncate_torn_tail = options.recovery_mode == RecoveryMode::TruncateTail
+                && Some(&gen) == gen_list.last();
+            seq = seq.max(load(
+                &path,
+                gen,
+                &mut reader,
+                &keys,
+                &indexes,
+                &mut segments,
+                truncate_torn_tail,
+            )?);
+            segments.get_mut(&gen).unwrap().size = reader.pos;
+            files.insert(gen, Arc::new(reader.reader.into_inner()));
         }
 
+        let uncompacted = segments.values().map(|segment| segment.stale).sum();
+        let mut live = 0;
+        let mut expiries = BTreeSet::new();
+        for entry in indexes.iter() {
+            let keyspace = *entry.key();
+            for entry in entry.value().iter() {
+                let version = entry.value();
+                live += version.pos().map_or(0, |cmd_pos| cmd_pos.len);
+                if let Some(expires_at) = version.expires_at() {
+                    expiries.insert((expires_at, keyspace, entry.key().clone()));
+                }
+            }
+        }
         let current_gen = gen_list.last().unwrap_or(&0) + 1;
-        let writer = new_log_file(&path, current_gen)?;
-        let safe_point = Arc::new(AtomicU64::new(0));
+        let (writer, cipher) = new_log_file(&path, current_gen, &options)?;
+        if options.durability == Durability::Always {
+            sync_dir(&path)?;
+        }
+        segments.insert(current_gen, Segment::default());
+        let active_gen = Arc::new(AtomicU64::new(current_gen));
 
         let reader = KvStoreReader {
             path: Arc::clone(&path),
-            safe_point,
-            readers: RefCell::new(readers),
+            options: Arc::clone(&options),
+            files: Arc::new(RwLock::new(OpenFiles {
+                files,
+                ..OpenFiles::default()
+            })),
+            active_gen: Arc::clone(&active_gen),
+            cache: Arc::new(ReadCache::new(options.cache_size)),
+            keys,
         };
-        let writer = KvStoreWriter {
-            reader: reader.clone(),
+        let compressor = Arc::new(Compressor::new(
+            options.compression,
+            options.compression_threshold,
+        ));
+        let compacting = Arc::new(AtomicBool::new(false));
+        let (sender, receiver) = compactor::channel();
+        let writer = Arc::new(Mutex::new(KvStoreWriter {
             writer,
             current_gen,
+            active_gen,
             uncompacted,
+            segments,
+            live,
+            expiries,
+            seq,
+            snapshots: Snapshots::new(),
+            versioned: BTreeSet::new(),
+            path: Arc::clone(&path),
+            options: Arc::clone(&options),
+            indexes: Arc::clone(&indexes),
+            registry,
+            compressor: Arc::clone(&compressor),
+            cipher,
+            compactor: sender.clone(),
+            compacting: Arc::clone(&compacting),
+        }));
+        let compactor = Compactor {
             path: Arc::clone(&path),
-            index: Arc::clone(&index),
+            options: Arc::clone(&options),
+            indexes: Arc::clone(&indexes),
+            writer: Arc::clone(&writer),
+            reader: reader.clone(),
+            compressor: Arc::clone(&compressor),
+            rate_limiter: Arc::new(RateLimiter::new(options.compaction_rate_limit)),
+            compacting,
+        };
+        let compactor = CompactorHandle::spawn(compactor, sender, receiver)?;
+        let syncer = match options.durability {
+            Durability::Interval(interval) => {
+                let writer = Arc::clone(&writer);
+                let syncer = PeriodicTask::spawn("kvs-sync", interval, move || {
+                    writer.lock().unwrap().sync()
+                })?;
+                Some(Arc::new(syncer))
+            }
+            Durability::Always | Durability::Never => None,
+        };
+        let reaper = {
+            let writer = Arc::clone(&writer);
+            PeriodicTask::spawn("kvs-reaper", options.reap_interval, move || {
+                writer.lock().unwrap().reap_expired()
+            })?
         };
 
-
-        Ok(KvStore {
+        let store = KvStore {
             path,
             reader,
-            writer: Arc::new(Mutex::new(writer)),
+            writer,
+            commits: Arc::new(CommitQueue::new()),
+            keyspace: DEFAULT_KEYSPACE,
+            index: keyspace_index(&indexes, DEFAULT_KEYSPACE).unwrap(),
+            compressor,
+            compactor: Arc::new(compactor),
+            _syncer: syncer,
+            _reaper: Arc::new(reaper),
+        };
+        if !legacy_gens.is_empty() {
+            legacy::replay(&store, &store.path, &legacy_gens)?;
+        }
+        Ok(store)
+    }
+
+    /// Sets the number of bytes per second compactions may read and write, or
+    /// lifts the limit with `None`.
+    ///
+    /// The new limit also applies to a compaction that is already running.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::InvalidOption` for a limit of zero.
+    pub fn set_compaction_rate_limit(&self, bytes_per_sec: Option<u64>) -> Result<()> {
+        options::check_rate_limit(bytes_per_sec)?;
+        self.compactor.set_rate_limit(bytes_per_sec);
+        Ok(())
+    }
+
+    /// Compacts every log file, rewriting all live records with the options the
+    /// store was opened with, and waits for the compaction to finish.
+    ///
+    /// Opening a store with a different `KvStoreOptions::compression` and
+    /// compacting it recompresses every value. A compaction that is already
+    /// running is waited for first. The log files a live snapshot may read are
+    /// left untouched.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during the compaction, and returns an error if
+    /// the compactor thread is gone.
+    pub fn compact(&self) -> Result<()> {
+        let done = self.lock_idle_writer()?.compact_all()?;
+        match done {
+            Some(done) => done.recv().unwrap_or_else(|_| {
+                Err(KvsError::StringError("compactor thread is gone".to_owned()))
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes a copy of the store to `dest` that `KvStore::open` can open,
+    /// while the store keeps serving reads and writes.
+    ///
+    /// The copy holds every write acknowledged before the call and none made
+    /// after it. Sealed log files and their hint files are hard-linked into
+    /// `dest` if the file system allows it and copied otherwise, and the part
+    /// of the active log file written so far is copied. Compactions leave the
+    /// log files alone until the copy is done. The copy of an encrypted store
+    /// is encrypted with the same keys. All the files of the copy, `dest` and
+    /// its parent directory are synced before the call returns.
+    ///
+    /// # Errors
+    ///
+    /// It returns an I/O error if `dest` already holds a store, and an error if
+    /// the compactor thread is gone, and propagates I/O errors during copying
+    /// the files.
+    pub fn checkpoint(&self, dest: impl AsRef<Path>) -> Result<()> {
+        let dest = dest.as_ref();
+        fs::create_dir_all(dest)?;
+        if !sorted_gen_list(dest)?.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{:?} already holds a store", dest),
+            )
+            .into());
+        }
+        // A running compaction deletes its victims once it is done, so it is
+        // waited for. A snapshot then keeps later compactions off the files.
+        let (seq, sealed, active_gen, active_len, registry) = {
+            let mut writer = self.lock_idle_writer()?;
+            writer.writer.flush()?;
+            let active_gen = writer.current_gen;
+            let sealed: Vec<u64> = writer
+                .segments
+                .keys()
+                .copied()
+                .filter(|&gen| gen != active_gen)
+                .collect();
+            (
+                writer.take_snapshot(),
+                sealed,
+                active_gen,
+                writer.writer.pos,
+                writer.registry.clone(),
+            )
+        };
+        let copied = copy_log_files(&self.path, dest, &sealed, active_gen, active_len)
+            .and_then(|()| registry.save(dest));
+        self.writer.lock().unwrap().release_snapshot(seq);
+        copied?;
+        sync_dir(dest)?;
+        match dest.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => sync_dir(parent),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns a handle to the keyspace `name` of the store, which shares the
+    /// log files, the cache and the background threads of this one.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyspaceNotFound` if there is no such keyspace.
+    pub fn keyspace(&self, name: &str) -> Result<KvStore> {
+        let writer = self.writer.lock().unwrap();
+        let keyspace = writer
+            .registry
+            .get(name)
+            .ok_or_else(|| KvsError::KeyspaceNotFound(name.to_owned()))?;
+        let index = writer
+            .index(keyspace)
+            .ok_or_else(|| KvsError::KeyspaceNotFound(name.to_owned()))?;
+        Ok(self.with_keyspace(keyspace, index))
+    }
+
+    /// Creates the empty keyspace `name` and returns a handle to it.
+    ///
+    /// Keyspaces are shared by all the handles of the store, whichever
+    /// keyspace they are a handle to, and survive reopening it. Every keyspace
+    /// has its own index, and all of them share the log files, whose records
+    /// carry the id of their keyspace.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::InvalidKeyspaceName` if `name` is empty or starts
+    /// with `__`, `KvsError::KeyspaceExists` if the keyspace already exists,
+    /// and propagates I/O errors during writing the list of keyspaces.
+    pub fn create_keyspace(&self, name: &str) -> Result<KvStore> {
+        check_keyspace_name(name)?;
+        let (keyspace, index) = self.writer.lock().unwrap().create_keyspace(name)?;
+        Ok(self.with_keyspace(keyspace, index))
+    }
+
+    /// Drops the keyspace `name` and all its keys.
+    ///
+    /// Dropping a keyspace costs the same whatever the number of keys: its
+    /// records are left in the log and dropped by the next compactions. The
+    /// handles to the keyspace read it empty from then on, and their writes
+    /// fail with `KvsError::KeyspaceDropped`. A keyspace created later under
+    /// the same name starts empty.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyspaceNotFound` if there is no such keyspace,
+    /// and propagates I/O errors during writing the list of keyspaces.
+    pub fn drop_keyspace(&self, name: &str) -> Result<()> {
+        self.writer.lock().unwrap().drop_keyspace(name)
+    }
+
+    /// Returns the names of the keyspaces of the store in order, without the
+    /// default keyspace, which `KvStore::open` returns.
+    pub fn list_keyspaces(&self) -> Vec<String> {
+        self.writer.lock().unwrap().registry.names()
+    }
+
+    /// Returns a handle to the keyspace with id `keyspace` and index `index`.
+    fn with_keyspace(&self, keyspace: u32, index: Arc<Index>) -> KvStore {
+        KvStore {
+            keyspace,
             index,
+            ..self.clone()
+        }
+    }
+
+    /// Returns the statistics of the store.
+    pub fn stats(&self) -> KvStoreStats {
+        let (cache_hits, cache_misses) = self.reader.cache.hits_and_misses();
+        let (value_bytes, stored_value_bytes) = self.compressor.totals();
+        KvStoreStats {
+            cache_hits,
+            cache_misses,
+            value_bytes,
+            stored_value_bytes,
+        }
+    }
+
+    /// Returns a read-only view of the store as of the last acknowledged write.
+    ///
+    /// The index keeps the versions of the keys the snapshot reads and
+    /// compactions keep the log files they are stored in until the snapshot is
+    /// dropped, so a long-lived snapshot holds on to disk space.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during opening the log files.
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        let mut writer = self.writer.lock().unwrap();
+        // A compaction drops its victims from the segments under the writer
+        // lock before deleting them, so all the generations can still be
+        // opened.
+        let files = self
+            .reader
+            .pin(writer.segments.keys().copied())?
+            .unwrap_or_default();
+        let seq = writer.take_snapshot();
+        Ok(Snapshot {
+            seq,
+            index: Arc::clone(&self.index),
+            reader: self.reader.clone(),
+            files,
+            writer: Arc::clone(&self.writer),
+        })
+    }
+
+    /// Locks the writer once no compaction is running.
+    ///
+    /// # Errors
+    ///
+    /// It returns an error if the compactor thread is gone, instead of waiting
+    /// for a compaction that will never finish.
+    fn lock_idle_writer(&self) -> Result<MutexGuard<'_, KvStoreWriter>> {
+        loop {
+            if !self.compactor.is_running() {
+                return Err(KvsError::StringError("compactor thread is gone".to_owned()));
+            }
+            let writer = self.writer.lock().unwrap();
+            if !writer.compacting.load(Ordering::SeqCst) {
+                return Ok(writer);
+            }
+            drop(writer);
+            thread::sleep(COMPACTION_POLL_INTERVAL);
+        }
+    }
+
+    /// Writes a command through the commit queue, sharing the flush and the sync
+    /// with concurrent writers.
+    fn commit(&self, update: Update) -> Result<()> {
+        self.commits.commit(update, |updates| {
+            self.writer.lock().unwrap().write_group(updates)
         })
     }
 }
 
 impl KvsEngine for KvStore {
-    /// Sets the value of a string key to a string.
+    type Transaction = KvStoreTransaction;
+
+    /// Sets the value of a key to a value, both arbitrary bytes.
     ///
     /// If the key already exists, the previous value will be overwritten.
     ///
     /// # Errors
     ///
     /// It propagates I/O or serialization errors during writing the log.
-    fn set(&self, key: String, value: String) -> Result<()> {
-        self.writer.lock().unwrap().set(key, value)
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.commit(Update::Command(Command::set(self.keyspace, key, value)))
     }
 
-    /// Gets the string value of a given string key.
+    /// Sets the value of a key until `ttl` has passed.
+    ///
+    /// The expiry time is stored in the log record. Reads ignore the key as
+    /// soon as it expires, and a background thread appends a remove for it
+    /// every `KvStoreOptions::reap_interval`.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn set_with_ttl_bytes(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.commit(Update::Command(Command::Set {
+            keyspace: self.keyspace,
+            key,
+            value,
+            expires_at: Some(expiry::expiry_time(ttl)),
+        }))
+    }
+
+    /// Gets the value of a given key.
     ///
     /// Returns `None` if the given key does not exist.
-    fn get(&self, key: String) -> Result<Option<String>> {
-        if let Some(cmd_pos) = self.index.get(&key) {
-            if let Command::Set { key, value } = self.reader.read_command(*cmd_pos.value())? {
-                Ok(Some(value))
-            } else {
-                Err(KvsError::UnexpectedCommandType)
-            }
-        } else {
-            Ok(None)
-        }
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let value = get_at(&self.index, &self.reader, &BTreeMap::new(), &key, LATEST)?;
+        Ok(value.map(|(_, value)| value))
     }
 
     /// Removes a given key.
@@ -297,24 +1289,266 @@ impl KvsEngine for KvStore {
     /// It returns `KvsError::KeyNotFound` if the given key is not found.
     ///
     /// It propagates I/O or serialization errors during writing the log.
-    fn remove(&self, key: String) -> Result<()> {
-        self.writer.lock().unwrap().remove(key)
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        self.commit(Update::Command(Command::remove(self.keyspace, key)))
+    }
+
+    /// Applies all the changes of `batch` atomically.
+    ///
+    /// The changes are appended to the log as a single record, which is
+    /// replayed as a whole or not at all when the store is opened.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let cmds = batch
+            .ops
+            .into_iter()
+            .map(|op| match op {
+                BatchOp::Set { key, value } => Command::set(self.keyspace, key, value),
+                BatchOp::Remove { key } => Command::remove(self.keyspace, key),
+            })
+            .collect();
+        self.commit(Update::Batch(cmds))
+    }
+
+    /// Sets `key` to `new`, or removes it if `new` is `None`, if and only if
+    /// its current value is `expected`.
+    ///
+    /// The current value is read and the new one written while holding the
+    /// writer lock, so no other write can happen in between. Unlike the other
+    /// writes, the swap is not batched with concurrent writes.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during reading or writing the
+    /// log.
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        let mut writer = self.writer.lock().unwrap();
+        // A compaction swaps index entries under the writer lock too, so the
+        // log file the entry points to cannot be deleted under us.
+        let now = expiry::now_millis();
+        let current = match self
+            .index
+            .get(&key)
+            .and_then(|entry| entry.value().live_pos(now))
+        {
+            Some(cmd_pos) => Some(self.reader.read_value(cmd_pos)?),
+            None => None,
+        };
+        if current != expected {
+            return Ok(false);
+        }
+        let cmd = match (new, current) {
+            (Some(value), _) => Command::set(self.keyspace, key, value),
+            (None, Some(_)) => Command::remove(self.keyspace, key),
+            // the key does not exist and should not
+            (None, None) => return Ok(true),
+        };
+        writer.write_group(vec![Update::Command(cmd)]).remove(0)?;
+        Ok(true)
+    }
+
+    /// Starts an optimistic transaction, whose commit checks the sequence
+    /// numbers of the keys it read.
+    fn transaction(&self) -> KvStoreTransaction {
+        KvStoreTransaction::new(self.clone())
+    }
+
+    /// Returns the key/value pairs whose keys fall in `range`, in key order.
+    ///
+    /// The positions of the values are read from the index when the scan
+    /// starts and the log files they point to are held open, so the values are
+    /// the ones set when the scan started even if a compaction moves them.
+    /// Values are read as the scan advances.
+    fn scan_bytes<R>(&self, range: R, options: ScanOptions) -> Result<ByteScan>
+    where
+        R: RangeBounds<Vec<u8>>,
+    {
+        scan_at(
+            &self.index,
+            &self.reader,
+            &BTreeMap::new(),
+            range,
+            options,
+            LATEST,
+        )
+    }
+}
+
+/// How often `KvStore::compact` checks whether a running compaction finished.
+const COMPACTION_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Sequence number of the reads of the latest values.
+const LATEST: u64 = u64::MAX;
+
+/// Gets the value of `key` as of sequence number `seq`, reading the log files
+/// in `pinned` instead of the shared handles.
+///
+/// Returns the value with the sequence number of the write that set it.
+fn get_at(
+    index: &Index,
+    reader: &KvStoreReader,
+    pinned: &BTreeMap<u64, Arc<File>>,
+    key: &[u8],
+    seq: u64,
+) -> Result<Option<(u64, Vec<u8>)>> {
+    loop {
+        let now = expiry::now_millis();
+        let (version, cmd_pos) = match index.get(key) {
+            Some(entry) => match entry.value().version_at(seq, now) {
+                Some(version) => version,
+                None => return Ok(None),
+            },
+            None => return Ok(None),
+        };
+        let value = match pinned.get(&cmd_pos.gen) {
+            Some(file) => reader.read_pinned_value(file, cmd_pos),
+            None => reader.read_value(cmd_pos),
+        };
+        match value {
+            Ok(value) => return Ok(Some((version, value))),
+            // The compactor moved the entry, or dropped it because it just
+            // expired, and deleted its old generation between the index
+            // lookup and the read, so look it up again.
+            Err(KvsError::IO(e))
+                if e.kind() == io::ErrorKind::NotFound
+                    && index
+                        .get(key)
+                        .and_then(|entry| entry.value().pos_at(seq, expiry::now_millis()))
+                        != Some(cmd_pos) => {}
+            Err(e) => return Err(e),
+        }
     }
 }
 
-/// Create a new log file with given generation number and add the reader to the readers map.
+/// Returns the key/value pairs whose keys fall in `range` as of sequence
+/// number `seq`, reading the log files in `pinned` instead of the shared
+/// handles.
+fn scan_at<R>(
+    index: &Index,
+    reader: &KvStoreReader,
+    pinned: &BTreeMap<u64, Arc<File>>,
+    range: R,
+    options: ScanOptions,
+    seq: u64,
+) -> Result<ByteScan>
+where
+    R: RangeBounds<Vec<u8>>,
+{
+    let range = (range.start_bound().cloned(), range.end_bound().cloned());
+    let limit = options.limit.unwrap_or(usize::MAX);
+    loop {
+        let now = expiry::now_millis();
+        let entries = index.range(range.clone()).filter_map(|entry| {
+            let cmd_pos = entry.value().pos_at(seq, now)?;
+            Some((entry.key().clone(), cmd_pos))
+        });
+        let entries: Vec<_> = if options.reverse {
+            entries.rev().take(limit).collect()
+        } else {
+            entries.take(limit).collect()
+        };
+        let gens = entries
+            .iter()
+            .map(|(_, pos)| pos.gen)
+            .filter(|gen| !pinned.contains_key(gen));
+        // A compaction deleted a log file the positions point to, so read
+        // the moved positions from the index again.
+        let mut files = match reader.pin(gens)? {
+            Some(files) => files,
+            None => continue,
+        };
+        files.extend(pinned.iter().map(|(&gen, file)| (gen, Arc::clone(file))));
+        let reader = reader.clone();
+        return Ok(ByteScan::new(entries.into_iter().map(move |(key, pos)| {
+            let value = reader.read_pinned_value(&files[&pos.gen], pos)?;
+            Ok((key, value))
+        })));
+    }
+}
+
+/// Create a new log file with given generation number and write its file header.
 ///
-/// Returns the writer to the log.
-fn new_log_file(path: &Path, gen: u64) -> Result<BufWriterWithPos<File>> {
-    let path = log_path(&path, gen);
-    let writer = BufWriterWithPos::new(
+/// Returns the writer to the log and the cipher of the file.
+fn new_log_file(
+    path: &Path,
+    gen: u64,
+    options: &KvStoreOptions,
+) -> Result<(BufWriterWithPos<File>, Option<Arc<FileCipher>>)> {
+    create_log_writer(&log_path(path, gen), options)
+}
+
+/// Create a log file at the given path and write its file header, encrypting
+/// the file if the options give a key.
+///
+/// Returns the writer to the log and the cipher of the file.
+fn create_log_writer(
+    path: &Path,
+    options: &KvStoreOptions,
+) -> Result<(BufWriterWithPos<File>, Option<Arc<FileCipher>>)> {
+    let mut writer = BufWriterWithPos::with_capacity(
+        options.write_buffer_size,
         OpenOptions::new()
-            .create(true)
+            .create_new(true)
             .write(true)
             .append(true)
-            .open(&path)?,
+            .open(path)?,
     )?;
-    Ok(writer)
+    let cipher = options
+        .encryption_key
+        .as_ref()
+        .map(|key| Arc::new(FileCipher::generate(key)));
+    record::write_file_header(&mut writer, cipher.as_deref())?;
+    writer.flush()?;
+    Ok((writer, cipher))
+}
+
+/// Copies the log files of a checkpoint from `src` to `dest`: the sealed
+/// generations with their hint files, and the first `active_len` bytes of the
+/// active generation, which end with a complete record.
+fn copy_log_files(
+    src: &Path,
+    dest: &Path,
+    sealed: &[u64],
+    active_gen: u64,
+    active_len: u64,
+) -> Result<()> {
+    for &gen in sealed {
+        link_or_copy(&log_path(src, gen), &log_path(dest, gen))?;
+        match link_or_copy(&hint::hint_path(src, gen), &hint::hint_path(dest, gen)) {
+            Ok(()) => {}
+            // only compactions write hint files
+            Err(KvsError::IO(e)) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+    let active = File::open(log_path(src, active_gen))?;
+    let mut copy = File::create(log_path(dest, active_gen))?;
+    io::copy(&mut active.take(active_len), &mut copy)?;
+    copy.sync_all()?;
+    Ok(())
+}
+
+/// Hard-links `src` to `dest`, or copies it if the file system cannot link
+/// them, for example because they are on different devices, and syncs `dest`.
+///
+/// A linked file is synced too, since the store may not have synced `src`.
+fn link_or_copy(src: &Path, dest: &Path) -> Result<()> {
+    if fs::hard_link(src, dest).is_err() {
+        fs::copy(src, dest)?;
+    }
+    File::open(dest)?.sync_all()?;
+    Ok(())
 }
 
 /// Returns sorted generation numbers in the given directory
@@ -336,37 +1570,247 @@ fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
 
 /// Load the whole log file and store value locations in the index map.
 ///
-/// Returns how many bytes can be saved after a compaction.
+/// If `truncate_torn_tail` is set, an incomplete record at the end of the file
+/// is dropped by truncating the file back to the end of the last complete record.
+///
+/// The bytes that can be saved after a compaction are added to `segments`.
+///
+/// Returns the highest sequence number of the log.
 fn load(
+    dir: &Path,
     gen: u64,
     reader: &mut BufReaderWithPos<File>,
-    index: &SkipMap<String, CommandPos>,
+    keys: &[EncryptionKey],
+    indexes: &Indexes,
+    segments: &mut BTreeMap<u64, Segment>,
+    truncate_torn_tail: bool,
 ) -> Result<u64> {
     // To make sure we read from the beginning of the file
-    let mut pos = reader.seek(SeekFrom::Start(0))?;
-    let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
-    let mut uncompacted = 0; // number of bytes that can be saved after a compaction
-    while let Some(cmd) = stream.next() {
-        let new_pos = stream.byte_offset() as u64;
-        match cmd? {
-            Command::Set { key, .. } => {
-                if let Some(old_cmd) = index.get(&key) {
-                    uncompacted += old_cmd.value().len;
-                }
-                index.insert(key, (gen, pos..new_pos).into());
+    reader.seek(SeekFrom::Start(0))?;
+    let cipher = match record::read_file_header(reader, gen, keys) {
+        Ok(Some(header)) => header.cipher,
+        Ok(None) => return Ok(0),
+        Err(KvsError::IncompleteRecord { .. }) if truncate_torn_tail => {
+            truncate_log(dir, gen, 0)?;
+            reader.seek(SeekFrom::Start(0))?;
+            return Ok(0);
+        }
+        Err(e) => return Err(e),
+    };
+    let now = expiry::now_millis();
+    let mut max_seq = 0;
+    let mut pos = reader.pos;
+    loop {
+        let record = match record::read_record(reader, gen, pos, cipher.as_deref()) {
+            Ok(Some(record)) => record,
+            Ok(None) => break,
+            Err(KvsError::IncompleteRecord { .. }) if truncate_torn_tail => {
+                truncate_log(dir, gen, pos)?;
+                reader.seek(SeekFrom::Start(pos))?;
+                break;
             }
-            Command::Remove { key } => {
-                if let Some(old_cmd) = index.remove(&key) {
-                    uncompacted += old_cmd.value().len;
+            Err(e) => return Err(e),
+        };
+        max_seq = max_seq.max(record.seq);
+        let mut commands_len = 0;
+        for (cmd, range) in record.cmds {
+            commands_len += range.end - range.start;
+            // the commands of dropped keyspaces are all stale
+            let index = match keyspace_index(indexes, cmd.keyspace()) {
+                Some(index) => index,
+                None => {
+                    count_stale(segments, &(gen, range).into());
+                    continue;
+                }
+            };
+            match cmd {
+                Command::Set {
+                    key, expires_at, ..
+                } if !expiry::is_expired(expires_at, now) => {
+                    let version = Version::set(record.seq, (gen, range).into(), expires_at);
+                    if let Some(old_cmd) = index_load(&index, &key, version) {
+                        count_stale(segments, &old_cmd);
+                    }
+                }
+                // a set that has expired removes the key like a remove
+                Command::Set { key, .. } | Command::Remove { key, .. } => {
+                    if let Some(old_cmd) = index_load(&index, &key, Version::removed(record.seq)) {
+                        count_stale(segments, &old_cmd);
+                    }
+                    // the "remove" command itself can be deleted in a compaction
+                    // once no older generation holds the key, so we count it as stale
+                    count_stale(segments, &(gen, range).into());
                 }
-                // the "remove" command itself can be deleted in the next compaction
-                // so we add its length to `uncompacted`
-                uncompacted += new_pos - pos;
             }
         }
-        pos = new_pos;
+        // the header of a batch, which a compaction drops too
+        count_stale(
+            segments,
+            &(gen, pos..pos + record.len - commands_len).into(),
+        );
+        pos += record.len;
     }
-    Ok(uncompacted)
+    Ok(max_seq)
+}
+
+/// Store the value locations listed in a hint file in the index map.
+///
+/// This has the same effect as `load` on the hinted log file, and returns the
+/// highest sequence number of the hinted records.
+fn load_hint(
+    gen: u64,
+    entries: Vec<HintEntry>,
+    indexes: &Indexes,
+    segments: &mut BTreeMap<u64, Segment>,
+) -> u64 {
+    let now = expiry::now_millis();
+    let mut max_seq = 0;
+    for entry in entries {
+        max_seq = max_seq.max(entry.seq);
+        let cmd_pos = (gen, entry.pos..entry.pos + entry.len).into();
+        let index = match keyspace_index(indexes, entry.keyspace) {
+            Some(index) => index,
+            None => {
+                count_stale(segments, &cmd_pos);
+                continue;
+            }
+        };
+        // a set that has expired removes the key like a remove
+        if entry.tombstone || expiry::is_expired(entry.expires_at, now) {
+            if let Some(old_cmd) = index_load(&index, &entry.key, Version::removed(entry.seq)) {
+                count_stale(segments, &old_cmd);
+            }
+            count_stale(segments, &cmd_pos);
+        } else {
+            let version = Version::set(entry.seq, cmd_pos, entry.expires_at);
+            if let Some(old_cmd) = index_load(&index, &entry.key, version) {
+                count_stale(segments, &old_cmd);
+            }
+        }
+    }
+    max_seq
+}
+
+/// Counts the record at `cmd_pos` as stale in its generation.
+///
+/// Returns the number of bytes counted, which is zero if the generation is not
+/// tracked because it is being compacted away.
+fn count_stale(segments: &mut BTreeMap<u64, Segment>, cmd_pos: &CommandPos) -> u64 {
+    match segments.get_mut(&cmd_pos.gen) {
+        Some(segment) => {
+            segment.stale += cmd_pos.len;
+            cmd_pos.len
+        }
+        None => 0,
+    }
+}
+
+/// Truncate the log file of the given generation to `len` bytes, dropping a torn
+/// record at its end.
+fn truncate_log(dir: &Path, gen: u64, len: u64) -> Result<()> {
+    let path = log_path(dir, gen);
+    let file = OpenOptions::new().write(true).open(&path)?;
+    let dropped = file.metadata()?.len() - len;
+    file.set_len(len)?;
+    file.sync_all()?;
+    warn!(
+        "{:?}: dropped {} bytes of an incomplete record at offset {}",
+        path, dropped, len
+    );
+    Ok(())
+}
+
+/// Sync a directory, so that the files created, renamed or deleted in it survive
+/// a power failure.
+fn sync_dir(dir: &Path) -> Result<()> {
+    // Directories cannot be opened as files on Windows, where the metadata is
+    // written through anyway.
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+/// Copies the error that failed a batch, so that every command of the batch can
+/// report it.
+fn copy_error(e: &KvsError) -> KvsError {
+    match e {
+        KvsError::IO(e) => io::Error::new(e.kind(), e.to_string()).into(),
+        e => KvsError::StringError(e.to_string()),
+    }
+}
+
+/// Read the record at the given `CommandPos` of `file` and decode it to `Command`.
+fn read_record(file: &File, cmd_pos: CommandPos, cipher: Option<&FileCipher>) -> Result<Command> {
+    let corruption = KvsError::Corruption {
+        gen: cmd_pos.gen,
+        pos: cmd_pos.pos,
+    };
+    let mut buf = vec![0; cmd_pos.len as usize];
+    match read_exact_at(file, &mut buf, cmd_pos.pos) {
+        Ok(()) => {}
+        // the record runs past the end of the file
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Err(corruption),
+        Err(e) => return Err(e.into()),
+    }
+    record::decode(&buf, cmd_pos.gen, cmd_pos.pos, cipher).ok_or(corruption)
+}
+
+/// Reads a file from a given position on, without using the cursor of the
+/// file, which is shared by all the readers.
+struct FileReader<'a> {
+    file: &'a File,
+    pos: u64,
+}
+
+impl Read for FileReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = read_at(self.file, buf, self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+/// Read up to `buf.len()` bytes of `file` at `offset`.
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::os::unix::fs::FileExt;
+    file.read_at(buf, offset)
+}
+
+/// Read up to `buf.len()` bytes of `file` at `offset`.
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::os::windows::fs::FileExt;
+    file.seek_read(buf, offset)
+}
+
+/// Read exactly `buf.len()` bytes of `file` at `offset`, without using the
+/// cursor of the file, which is shared by all the readers.
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, offset)
+}
+
+/// Read exactly `buf.len()` bytes of `file` at `offset`. The cursor of the
+/// file moves, but no reader depends on it.
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
 }
 
 fn log_path(dir: &Path, gen: u64) -> PathBuf {
@@ -374,30 +1818,304 @@ fn log_path(dir: &Path, gen: u64) -> PathBuf {
 }
 
 /// Struct representing a command
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Debug)]
 enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
+    Set {
+        // id of the keyspace of the key
+        keyspace: u32,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        // expiry time, in milliseconds since the Unix epoch
+        expires_at: Option<u64>,
+    },
+    Remove {
+        keyspace: u32,
+        key: Vec<u8>,
+    },
 }
 
 impl Command {
-    fn set(key: String, value: String) -> Command {
-        Command::Set { key, value }
+    fn set(keyspace: u32, key: Vec<u8>, value: Vec<u8>) -> Command {
+        Command::Set {
+            keyspace,
+            key,
+            value,
+            expires_at: None,
+        }
+    }
+
+    fn remove(keyspace: u32, key: Vec<u8>) -> Command {
+        Command::Remove { keyspace, key }
+    }
+
+    fn keyspace(&self) -> u32 {
+        match self {
+            Command::Set { keyspace, .. } | Command::Remove { keyspace, .. } => *keyspace,
+        }
     }
 
-    fn remove(key: String) -> Command {
-        Command::Remove { key }
+    fn key(&self) -> &[u8] {
+        match self {
+            Command::Set { key, .. } | Command::Remove { key, .. } => key,
+        }
     }
 }
 
-/// Represents the position and length of a json-serialized command in the log
-#[derive(Clone, Copy)]
+/// Update queued by a writer.
+enum Update {
+    /// A single set or remove. Removing a key that does not exist fails.
+    Command(Command),
+    /// The commands of a `WriteBatch`, written as one record. Removing a key
+    /// that does not exist does nothing.
+    Batch(Vec<Command>),
+}
+
+/// Returns whether the key of `cmd` exists in `index`, the index of its
+/// keyspace, after the commands of the group written so far, which recorded
+/// the keys they touched in `exists`.
+fn key_exists(index: &Index, exists: &HashMap<(u32, Vec<u8>), bool>, cmd: &Command) -> bool {
+    match exists.get(&(cmd.keyspace(), cmd.key().to_vec())) {
+        Some(&found) => found,
+        None => index
+            .get(cmd.key())
+            .and_then(|entry| entry.value().live_pos(expiry::now_millis()))
+            .is_some(),
+    }
+}
+
+/// Size and stale bytes of a generation.
+#[derive(Clone, Copy, Debug, Default)]
+struct Segment {
+    size: u64,
+    // the number of bytes of stale commands that could be dropped by a
+    // compaction
+    stale: u64,
+}
+
+/// Represents the position and length of an encoded record in the log
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 struct CommandPos {
     gen: u64,
     pos: u64,
     len: u64,
 }
 
+/// In-memory index from every key to the versions of its record.
+type Index = SkipMap<Vec<u8>, IndexSlot>;
+
+/// Indexes of the keyspaces of a store, by keyspace id.
+type Indexes = SkipMap<u32, Arc<Index>>;
+
+/// Returns the index of the keyspace with id `keyspace`, or `None` if the
+/// keyspace does not exist.
+fn keyspace_index(indexes: &Indexes, keyspace: u32) -> Option<Arc<Index>> {
+    indexes
+        .get(&keyspace)
+        .map(|entry| Arc::clone(entry.value()))
+}
+
+/// Live snapshots of a store, by sequence number.
+type Snapshots = BTreeMap<u64, SnapshotRefs>;
+
+/// Snapshots taken at the same sequence number.
+struct SnapshotRefs {
+    // generation written to when they were taken
+    gen: u64,
+    count: usize,
+}
+
+/// Versions of the record of a key in the `Index`, oldest first.
+///
+/// Replacing an entry of a `SkipMap` removes the old entry before inserting
+/// the new one, so a concurrent lookup could miss the key. The versions of a
+/// key that is already indexed are updated in place instead, and a key only
+/// leaves the index when it is removed and no snapshot reads it anymore.
+///
+/// Only the latest version is kept unless a snapshot reads an older one. The
+/// latest version is a removal if the key was removed while a snapshot that
+/// reads it was alive.
+struct IndexSlot(Mutex<Vec<Version>>);
+
+/// Version of a key written with sequence number `seq`.
+#[derive(Clone, Copy)]
+struct Version {
+    seq: u64,
+    // `None` if the write removed the key
+    entry: Option<IndexEntry>,
+}
+
+#[derive(Clone, Copy)]
+struct IndexEntry {
+    cmd_pos: CommandPos,
+    // expiry time of the key, in milliseconds since the Unix epoch
+    expires_at: Option<u64>,
+}
+
+/// What is left of the versions of a key once those no snapshot reads are
+/// dropped.
+enum History {
+    /// A single value.
+    Latest,
+    /// Older versions or a removal read by snapshots.
+    Versions,
+    /// A single removal, which no snapshot reads.
+    Removed,
+}
+
+impl Version {
+    fn set(seq: u64, cmd_pos: CommandPos, expires_at: Option<u64>) -> Version {
+        Version {
+            seq,
+            entry: Some(IndexEntry {
+                cmd_pos,
+                expires_at,
+            }),
+        }
+    }
+
+    fn removed(seq: u64) -> Version {
+        Version { seq, entry: None }
+    }
+}
+
+impl IndexSlot {
+    fn latest(&self) -> Option<IndexEntry> {
+        self.0
+            .lock()
+            .unwrap()
+            .last()
+            .and_then(|version| version.entry)
+    }
+
+    /// Returns the position of the latest record of the key, unless the key
+    /// was removed.
+    fn pos(&self) -> Option<CommandPos> {
+        self.latest().map(|entry| entry.cmd_pos)
+    }
+
+    fn expires_at(&self) -> Option<u64> {
+        self.latest().and_then(|entry| entry.expires_at)
+    }
+
+    /// Returns the position of the record of the key unless the key expired
+    /// at `now`.
+    fn live_pos(&self, now: u64) -> Option<CommandPos> {
+        self.pos_at(LATEST, now)
+    }
+
+    /// Returns the position of the record of the key as of sequence number
+    /// `seq`, unless the key did not exist then or expired at `now`.
+    fn pos_at(&self, seq: u64, now: u64) -> Option<CommandPos> {
+        self.version_at(seq, now).map(|(_, cmd_pos)| cmd_pos)
+    }
+
+    /// Returns the sequence number and the position of the version of the key
+    /// as of sequence number `seq`, unless the key did not exist then or
+    /// expired at `now`.
+    fn version_at(&self, seq: u64, now: u64) -> Option<(u64, CommandPos)> {
+        let versions = self.0.lock().unwrap();
+        let version = versions.iter().rev().find(|version| version.seq <= seq)?;
+        let entry = version.entry?;
+        if expiry::is_expired(entry.expires_at, now) {
+            return None;
+        }
+        Some((version.seq, entry.cmd_pos))
+    }
+
+    /// Moves the versions of the key stored at `old_pos` to `new_pos`, which
+    /// keep their expiry.
+    fn relocate(&self, old_pos: CommandPos, new_pos: CommandPos) {
+        for version in self.0.lock().unwrap().iter_mut() {
+            match &mut version.entry {
+                Some(entry) if entry.cmd_pos == old_pos => entry.cmd_pos = new_pos,
+                _ => {}
+            }
+        }
+    }
+
+    /// Records a new version of the key and drops the versions no snapshot
+    /// reads anymore.
+    ///
+    /// Returns the position of the previous version, if it was not a removal.
+    fn push(&self, version: Version, snapshots: &Snapshots) -> (Option<CommandPos>, History) {
+        let mut versions = self.0.lock().unwrap();
+        let old_pos = versions
+            .last()
+            .and_then(|version| version.entry)
+            .map(|entry| entry.cmd_pos);
+        versions.push(version);
+        (old_pos, prune(&mut versions, snapshots))
+    }
+
+    /// Drops the versions of the key no snapshot reads anymore.
+    fn prune(&self, snapshots: &Snapshots) -> History {
+        prune(&mut self.0.lock().unwrap(), snapshots)
+    }
+}
+
+/// Drops the versions that are neither the latest one nor the one read by one
+/// of the `snapshots`.
+fn prune(versions: &mut Vec<Version>, snapshots: &Snapshots) -> History {
+    let mut kept = Vec::with_capacity(versions.len());
+    for (i, version) in versions.iter().enumerate() {
+        let read = match versions.get(i + 1) {
+            Some(next) => snapshots.range(version.seq..next.seq).next().is_some(),
+            None => true,
+        };
+        if read {
+            kept.push(*version);
+        }
+    }
+    *versions = kept;
+    match versions.as_slice() {
+        [version] if version.entry.is_none() => History::Removed,
+        [_] => History::Latest,
+        _ => History::Versions,
+    }
+}
+
+/// Records a new version of `key` in the index, dropping the versions none of
+/// the `snapshots` reads anymore.
+///
+/// Returns the position the key pointed to before, if it was not removed, and
+/// whether older versions or a removal are left for the snapshots.
+///
+/// Concurrent updates of the index must be serialized by the caller, which the
+/// writer lock does.
+fn index_update(
+    index: &Index,
+    key: &[u8],
+    version: Version,
+    snapshots: &Snapshots,
+) -> (Option<CommandPos>, bool) {
+    let slot = match index.get(key) {
+        Some(slot) => slot,
+        None => {
+            if version.entry.is_some() {
+                index.insert(key.to_vec(), IndexSlot(Mutex::new(vec![version])));
+            }
+            return (None, false);
+        }
+    };
+    let (old_pos, history) = slot.value().push(version, snapshots);
+    match history {
+        History::Latest => (old_pos, false),
+        History::Versions => (old_pos, true),
+        History::Removed => {
+            index.remove(key);
+            (old_pos, false)
+        }
+    }
+}
+
+/// Records a version of `key` read from the log while the store is opened,
+/// when there is no snapshot yet.
+///
+/// Returns the position the key pointed to before, if it was not removed.
+fn index_load(index: &Index, key: &[u8], version: Version) -> Option<CommandPos> {
+    index_update(index, key, version, &Snapshots::new()).0
+}
+
 impl From<(u64, Range<u64>)> for CommandPos {
     fn from((gen, range): (u64, Range<u64>)) -> Self {
         CommandPos {
@@ -414,10 +2132,10 @@ struct BufReaderWithPos<R: Read + Seek> {
 }
 
 impl<R: Read + Seek> BufReaderWithPos<R> {
-    fn new(mut inner: R) -> Result<Self> {
+    fn with_capacity(capacity: usize, mut inner: R) -> Result<Self> {
         let pos = inner.seek(SeekFrom::Current(0))?;
         Ok(BufReaderWithPos {
-            reader: BufReader::new(inner),
+            reader: BufReader::with_capacity(capacity, inner),
             pos,
         })
     }
@@ -444,15 +2162,31 @@ struct BufWriterWithPos<W: Write + Seek> {
 }
 
 impl<W: Write + Seek> BufWriterWithPos<W> {
-    fn new(mut inner: W) -> Result<Self> {
+    fn with_capacity(capacity: usize, mut inner: W) -> Result<Self> {
         let pos = inner.seek(SeekFrom::Current(0))?;
         Ok(BufWriterWithPos {
-            writer: BufWriter::new(inner),
+            writer: BufWriter::with_capacity(capacity, inner),
             pos,
         })
     }
 }
 
+impl BufWriterWithPos<File> {
+    /// Drops the buffered bytes without writing them and cuts the file back to
+    /// `len` bytes.
+    fn discard_from(&mut self, len: u64) -> io::Result<()> {
+        let file = self.writer.get_ref().try_clone()?;
+        file.set_len(len)?;
+        let capacity = self.writer.capacity();
+        let discarded =
+            std::mem::replace(&mut self.writer, BufWriter::with_capacity(capacity, file));
+        // unlike dropping it, taking it apart does not flush the buffer
+        let _ = discarded.into_parts();
+        self.pos = len;
+        Ok(())
+    }
+}
+
 impl<W: Write + Seek> Write for BufWriterWithPos<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let len = self.writer.write(buf)?;
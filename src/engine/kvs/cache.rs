@@ -0,0 +1,110 @@
+//! Cache of the values read from the log files.
+//!
+//! Values are keyed by the `CommandPos` of their record. A record never changes
+//! once it is written and a generation number is never reused, so an entry
+//! never goes stale: an overwrite or a compaction points the index to another
+//! position and the old entry just ages out.
+//!
+//! The cache is split into shards, each with its own lock and its own share of
+//! the memory budget, so that concurrent readers rarely contend.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use lru::LruCache;
+
+use super::CommandPos;
+
+/// Number of shards of the cache.
+const SHARDS: usize = 16;
+
+/// Memory charged for every entry on top of its value.
+const ENTRY_OVERHEAD: usize = 64;
+
+pub struct ReadCache {
+    shards: Vec<Mutex<Shard>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct Shard {
+    entries: LruCache<CommandPos, Vec<u8>>,
+    // memory charged for the entries
+    size: usize,
+    capacity: usize,
+}
+
+impl ReadCache {
+    /// Creates a cache using at most `capacity` bytes. A capacity of zero
+    /// disables the cache.
+    pub fn new(capacity: usize) -> ReadCache {
+        let shards = (0..SHARDS)
+            .map(|_| {
+                Mutex::new(Shard {
+                    entries: LruCache::unbounded(),
+                    size: 0,
+                    capacity: capacity / SHARDS,
+                })
+            })
+            .collect();
+        ReadCache {
+            shards,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the value of the record at `cmd_pos` if it is cached.
+    pub fn get(&self, cmd_pos: &CommandPos) -> Option<Vec<u8>> {
+        let value = self
+            .shard(cmd_pos)
+            .lock()
+            .unwrap()
+            .entries
+            .get(cmd_pos)
+            .cloned();
+        let counter = if value.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        value
+    }
+
+    /// Caches the value of the record at `cmd_pos`, evicting the least recently
+    /// used values of its shard to make room.
+    pub fn insert(&self, cmd_pos: CommandPos, value: Vec<u8>) {
+        let charge = value.len() + ENTRY_OVERHEAD;
+        let mut shard = self.shard(&cmd_pos).lock().unwrap();
+        if charge > shard.capacity {
+            return;
+        }
+        if let Some(old) = shard.entries.put(cmd_pos, value) {
+            shard.size -= old.len() + ENTRY_OVERHEAD;
+        }
+        shard.size += charge;
+        while shard.size > shard.capacity {
+            match shard.entries.pop_lru() {
+                Some((_, evicted)) => shard.size -= evicted.len() + ENTRY_OVERHEAD,
+                None => break,
+            }
+        }
+    }
+
+    /// Returns the number of lookups that found and did not find their value.
+    pub fn hits_and_misses(&self) -> (u64, u64) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+
+    fn shard(&self, cmd_pos: &CommandPos) -> &Mutex<Shard> {
+        let mut hasher = DefaultHasher::new();
+        cmd_pos.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % SHARDS]
+    }
+}
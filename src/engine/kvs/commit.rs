@@ -0,0 +1,117 @@
+//! Group commit.
+//!
+//! Writers do not take the writer lock one at a time. Each one queues its
+//! update, and the first writer to find no batch in progress becomes the
+//! leader: it takes every queued update, appends them to the log with a single
+//! flush (and a single sync in `Durability::Always` mode), and hands every
+//! queued writer its own result. Writers arriving in the meantime queue up for
+//! the next batch, so under load the cost of a sync is shared by every writer
+//! that waited for it.
+
+use std::collections::HashMap;
+use std::mem;
+use std::sync::{Condvar, Mutex};
+use std::thread;
+
+use crate::{KvsError, Result};
+
+/// Queue of the updates waiting to be committed.
+pub struct CommitQueue<T> {
+    state: Mutex<State<T>>,
+    // notified every time a batch is committed
+    committed: Condvar,
+}
+
+struct State<T> {
+    queued: Vec<(u64, T)>,
+    // results of committed updates not yet picked up by their writers
+    results: HashMap<u64, Result<()>>,
+    next_ticket: u64,
+    // whether a leader is writing a batch
+    leader: bool,
+}
+
+impl<T> CommitQueue<T> {
+    pub fn new() -> CommitQueue<T> {
+        CommitQueue {
+            state: Mutex::new(State {
+                queued: Vec::new(),
+                results: HashMap::new(),
+                next_ticket: 0,
+                leader: false,
+            }),
+            committed: Condvar::new(),
+        }
+    }
+
+    /// Queues `update` and waits until it is committed.
+    ///
+    /// If no batch is in progress, the calling thread commits every queued
+    /// update itself by calling `write_batch`, which must return one result
+    /// per update, in order.
+    pub fn commit<F>(&self, update: T, write_batch: F) -> Result<()>
+    where
+        F: FnOnce(Vec<T>) -> Vec<Result<()>>,
+    {
+        let mut state = self.state.lock().unwrap();
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.queued.push((ticket, update));
+        loop {
+            if let Some(result) = state.results.remove(&ticket) {
+                return result;
+            }
+            if !state.leader {
+                break;
+            }
+            state = self.committed.wait(state).unwrap();
+        }
+
+        // Our update is still queued, so we lead the next batch.
+        state.leader = true;
+        let (tickets, updates): (Vec<_>, Vec<_>) = mem::take(&mut state.queued).into_iter().unzip();
+        drop(state);
+
+        let mut leader = Leader {
+            queue: self,
+            tickets,
+        };
+        let results = write_batch(updates);
+        debug_assert_eq!(results.len(), leader.tickets.len());
+        let tickets = mem::take(&mut leader.tickets);
+        let mut state = self.state.lock().unwrap();
+        state.results.extend(tickets.into_iter().zip(results));
+        let result = state.results.remove(&ticket).expect("missing result");
+        drop(state);
+        // wakes up the writers of the batch
+        drop(leader);
+        result
+    }
+}
+
+/// Hands the leadership back when the batch is done.
+///
+/// If the leader panicked while writing the batch, the other writers of the
+/// batch get an error instead of waiting forever.
+struct Leader<'a, T> {
+    queue: &'a CommitQueue<T>,
+    tickets: Vec<u64>,
+}
+
+impl<T> Drop for Leader<'_, T> {
+    fn drop(&mut self) {
+        let mut state = self.queue.state.lock().unwrap();
+        if thread::panicking() {
+            for &ticket in &self.tickets {
+                state.results.insert(
+                    ticket,
+                    Err(KvsError::StringError(
+                        "the write failed in another thread".to_owned(),
+                    )),
+                );
+            }
+        }
+        state.leader = false;
+        self.queue.committed.notify_all();
+    }
+}
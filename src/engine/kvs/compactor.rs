@@ -0,0 +1,436 @@
+//! Background compaction.
+//!
+//! Once enough stale data has piled up, the writer picks the generations with
+//! the highest fraction of stale bytes as victims, reserves a compaction
+//! generation, moves on to a fresh generation for new writes and hands the
+//! victims over to the compactor thread. The compactor copies the live records
+//! of the victims into `<gen>.compact` without holding the writer lock, renames
+//! the file to `<gen>.log` once it is complete and synced, swaps the index
+//! positions of every entry that was not overwritten in the meantime and
+//! deletes the victims. The other generations are left untouched, so the cost
+//! of a compaction grows with the garbage it collects.
+//!
+//! The compaction generation sorts after the victims and before every
+//! generation written to while it runs, so replaying the logs in generation
+//! order still gives the latest value of every key.
+//!
+//! The records read and written by a compaction go through a `RateLimiter`, so
+//! that a compaction leaves most of the disk to gets and sets.
+//!
+//! Every record copied is encoded again, so its value is compressed with the
+//! current `KvStoreOptions::compression` and encrypted with the current
+//! `KvStoreOptions::encryption_key`. Compacting a store opened with a new key
+//! and the old one therefore moves all its live records to the new key.
+//!
+//! The records of a dropped keyspace are all stale, so a compaction drops
+//! them, removes included.
+//!
+//! A compaction interrupted by a shutdown or a crash only leaves a `.compact`
+//! file behind, which is never read and is removed when the store is opened.
+
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use log::{error, warn};
+
+use super::compression::Compressor;
+use super::encryption::FileCipher;
+use super::hint::{self, HintEntry};
+use super::rate_limit::RateLimiter;
+use super::record::Encoder;
+use super::{
+    create_log_writer, keyspace_index, log_path, record, sorted_gen_list, sync_dir,
+    BufReaderWithPos, Command, CommandPos, Indexes, KvStoreOptions, KvStoreReader, KvStoreWriter,
+    Segment,
+};
+use crate::engine::expiry;
+use crate::Result;
+
+/// Message sent to the compactor thread.
+pub enum Message {
+    /// Compact the victim generations into the given generation, and send the
+    /// result to `done` if any.
+    Compact {
+        gen: u64,
+        victims: Vec<u64>,
+        done: Option<Sender<Result<()>>>,
+    },
+    /// Stop the compactor thread.
+    Shutdown,
+}
+
+/// State owned by the compactor thread.
+pub struct Compactor {
+    pub path: Arc<PathBuf>,
+    pub options: Arc<KvStoreOptions>,
+    pub indexes: Arc<Indexes>,
+    pub writer: Arc<Mutex<KvStoreWriter>>,
+    // closes the handles of the compacted generations
+    pub reader: KvStoreReader,
+    // compresses the copied values
+    pub compressor: Arc<Compressor>,
+    // limits the bytes read and written by compactions
+    pub rate_limiter: Arc<RateLimiter>,
+    // set by the writer when it hands over a compaction and cleared here when
+    // the compaction is finished
+    pub compacting: Arc<AtomicBool>,
+}
+
+/// Handle to the compactor thread, shared by all clones of a `KvStore`.
+///
+/// Dropping the last handle stops the thread and waits for it, abandoning a
+/// compaction that is still copying entries.
+pub struct CompactorHandle {
+    sender: Mutex<Sender<Message>>,
+    shutdown: Arc<AtomicBool>,
+    rate_limiter: Arc<RateLimiter>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// Creates the channel used to talk to the compactor thread.
+pub fn channel() -> (Sender<Message>, Receiver<Message>) {
+    mpsc::channel()
+}
+
+impl CompactorHandle {
+    /// Spawns the compactor thread.
+    pub fn spawn(
+        compactor: Compactor,
+        sender: Sender<Message>,
+        receiver: Receiver<Message>,
+    ) -> Result<CompactorHandle> {
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let thread_shutdown = Arc::clone(&shutdown);
+        let rate_limiter = Arc::clone(&compactor.rate_limiter);
+        let thread = thread::Builder::new()
+            .name("kvs-compactor".to_owned())
+            .spawn(move || compactor.run(receiver, &thread_shutdown))?;
+        Ok(CompactorHandle {
+            sender: Mutex::new(sender),
+            shutdown,
+            rate_limiter,
+            thread: Some(thread),
+        })
+    }
+
+    /// Sets the number of bytes per second compactions may read and write, or
+    /// lifts the limit with `None`.
+    pub fn set_rate_limit(&self, bytes_per_sec: Option<u64>) {
+        self.rate_limiter.set_rate(bytes_per_sec);
+    }
+
+    /// Returns whether the compactor thread is still running, which it is
+    /// until the handle is dropped unless it panicked.
+    pub fn is_running(&self) -> bool {
+        self.thread
+            .as_ref()
+            .is_some_and(|thread| !thread.is_finished())
+    }
+}
+
+impl Drop for CompactorHandle {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        // The thread only exits after receiving this message, so a failed send
+        // means it is already gone.
+        let _ = self.sender.lock().unwrap().send(Message::Shutdown);
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("compactor thread panicked");
+            }
+        }
+    }
+}
+
+/// Clears the `compacting` flag when the compactor thread exits, even by a
+/// panic, so that no one waits for a compaction that will never finish.
+struct ClearOnExit<'a>(&'a AtomicBool);
+
+impl Drop for ClearOnExit<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+impl Compactor {
+    fn run(self, receiver: Receiver<Message>, shutdown: &AtomicBool) {
+        let _clear = ClearOnExit(&self.compacting);
+        for message in receiver {
+            match message {
+                Message::Compact { gen, victims, done } => {
+                    let result = self.compact(gen, &victims, shutdown);
+                    if let Err(e) = &result {
+                        error!("compaction into generation {} failed: {}", gen, e);
+                    }
+                    self.compacting.store(false, Ordering::SeqCst);
+                    if let Some(done) = done {
+                        let _ = done.send(result);
+                    }
+                }
+                Message::Shutdown => break,
+            }
+        }
+    }
+
+    fn compact(&self, compaction_gen: u64, victims: &[u64], shutdown: &AtomicBool) -> Result<()> {
+        let tmp_path = compaction_path(&self.path, compaction_gen);
+        let compacted = match self.copy_live_records(compaction_gen, victims, &tmp_path, shutdown) {
+            Ok(Some(compacted)) => compacted,
+            Ok(None) => {
+                warn!("compaction into generation {} abandoned", compaction_gen);
+                fs::remove_file(&tmp_path)?;
+                return Ok(());
+            }
+            Err(e) => {
+                let _ = fs::remove_file(&tmp_path);
+                return Err(e);
+            }
+        };
+        // no generation is needed if nothing was live in the victims
+        let empty = compacted.hint_entries.is_empty();
+        if empty {
+            fs::remove_file(&tmp_path)?;
+        } else {
+            fs::rename(&tmp_path, log_path(&self.path, compaction_gen))?;
+            sync_dir(&self.path)?;
+            hint::write_hint(
+                &self.path,
+                compaction_gen,
+                &compacted.hint_entries,
+                compacted.cipher.as_deref(),
+            )?;
+        }
+
+        {
+            let mut writer = self.writer.lock().unwrap();
+            let mut stale = 0;
+            for (keyspace, key, old_pos, new_pos) in compacted.moved {
+                // the keyspace may have been dropped while we were copying
+                let index = match keyspace_index(&self.indexes, keyspace) {
+                    Some(index) => index,
+                    None => {
+                        stale += new_pos.len;
+                        continue;
+                    }
+                };
+                let slot = match index.get(&key) {
+                    Some(slot) => slot,
+                    None => {
+                        stale += new_pos.len;
+                        continue;
+                    }
+                };
+                // overwritten or removed while we were copying, so the copy
+                // is already stale
+                if slot.value().pos() != Some(old_pos) {
+                    stale += new_pos.len;
+                }
+                // a snapshot may still read an overwritten version
+                slot.value().relocate(old_pos, new_pos);
+            }
+            let segment = Segment {
+                size: compacted.size,
+                stale,
+            };
+            writer.replace_segments(victims, Some((compaction_gen, segment)).filter(|_| !empty));
+        }
+
+        // remove the compacted log files
+        // Note that actually these files are not deleted immediately because reads and scans
+        // in progress may still hold file handles. On Unix, the files will be deleted after
+        // all the handles are closed. On Windows, the deletions below will fail and stale
+        // files are expected to be deleted when the store is compacted again.
+        // The readers are told first, so that a scan failing to open a deleted file knows
+        // it was compacted away.
+        self.reader.close(victims);
+        for &victim in victims {
+            let file_path = log_path(&self.path, victim);
+            if let Err(e) = fs::remove_file(&file_path) {
+                error!("{:?} cannot be deleted: {}", file_path, e);
+            }
+            if let Err(e) = hint::remove_hint(&self.path, victim) {
+                error!("hint of generation {} cannot be deleted: {}", victim, e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Copies the live records of the victim generations into a new log file at
+    /// `tmp_path` and syncs it.
+    ///
+    /// A set is live if the index still points to it and it has not expired. A
+    /// remove is kept if its key has not been set again and an older generation
+    /// that is not compacted may still hold the key, which the remove has to
+    /// keep hiding. An expired set that still has to hide older records is
+    /// copied as a remove.
+    ///
+    /// Returns `None` if a shutdown was requested before the copy finished.
+    fn copy_live_records(
+        &self,
+        compaction_gen: u64,
+        victims: &[u64],
+        tmp_path: &Path,
+        shutdown: &AtomicBool,
+    ) -> Result<Option<Compacted>> {
+        let oldest_kept = sorted_gen_list(&self.path)?
+            .into_iter()
+            .find(|gen| !victims.contains(gen));
+        let (mut compaction_writer, cipher) = create_log_writer(tmp_path, &self.options)?;
+        let encoder = Encoder {
+            compressor: &self.compressor,
+            cipher: cipher.as_deref(),
+            gen: compaction_gen,
+        };
+        let mut moved = Vec::new();
+        let mut hint_entries = Vec::new();
+        let mut tombstones = HashSet::new();
+        let now = expiry::now_millis();
+        for &gen in victims {
+            let mut reader = BufReaderWithPos::with_capacity(
+                self.options.read_buffer_size,
+                File::open(log_path(&self.path, gen))?,
+            )?;
+            let victim_cipher = match record::read_file_header(&mut reader, gen, &self.reader.keys)?
+            {
+                Some(header) => header.cipher,
+                None => continue,
+            };
+            let mut pos = reader.pos;
+            while let Some(record) =
+                record::read_record(&mut reader, gen, pos, victim_cipher.as_deref())?
+            {
+                self.rate_limiter.acquire(record.len, shutdown);
+                if shutdown.load(Ordering::SeqCst) {
+                    return Ok(None);
+                }
+                pos += record.len;
+                // the commands of a batch are copied as single records, which
+                // keep the sequence number of the batch
+                for (cmd, range) in record.cmds {
+                    let old_pos: CommandPos = (gen, range).into();
+                    let hides_older = matches!(oldest_kept, Some(oldest) if oldest < gen);
+                    let keyspace = cmd.keyspace();
+                    let index = match keyspace_index(&self.indexes, keyspace) {
+                        Some(index) => index,
+                        None => continue,
+                    };
+                    let cmd = match cmd {
+                        Command::Set {
+                            ref key,
+                            expires_at,
+                            ..
+                        } if !expiry::is_expired(expires_at, now) => match index.get(key) {
+                            Some(entry) if entry.value().pos() == Some(old_pos) => cmd,
+                            _ => continue,
+                        },
+                        // An expired set is dropped, but it hides the older
+                        // records of its key like a remove until the key is set
+                        // again.
+                        Command::Set { key, .. } => {
+                            let set_again = matches!(
+                                index.get(&key).and_then(|entry| entry.value().pos()),
+                                Some(pos) if pos != old_pos
+                            );
+                            if !hides_older
+                                || set_again
+                                || !tombstones.insert((keyspace, key.clone()))
+                            {
+                                continue;
+                            }
+                            Command::remove(keyspace, key)
+                        }
+                        Command::Remove { key, .. } => {
+                            if !hides_older
+                                || index
+                                    .get(&key)
+                                    .and_then(|entry| entry.value().pos())
+                                    .is_some()
+                                || !tombstones.insert((keyspace, key.clone()))
+                            {
+                                continue;
+                            }
+                            Command::remove(keyspace, key)
+                        }
+                    };
+                    let new_pos = compaction_writer.pos;
+                    let encoded = record::encode(&cmd, record.seq, new_pos, encoder);
+                    // the copy may be compressed or encrypted differently
+                    self.rate_limiter.acquire(encoded.len() as u64, shutdown);
+                    compaction_writer.write_all(&encoded)?;
+                    let new_pos: CommandPos =
+                        (compaction_gen, new_pos..compaction_writer.pos).into();
+                    match cmd {
+                        Command::Set {
+                            key, expires_at, ..
+                        } => {
+                            hint_entries.push(HintEntry {
+                                keyspace,
+                                key: key.clone(),
+                                pos: new_pos.pos,
+                                len: new_pos.len,
+                                tombstone: false,
+                                expires_at,
+                                seq: record.seq,
+                            });
+                            moved.push((keyspace, key, old_pos, new_pos));
+                        }
+                        Command::Remove { key, .. } => hint_entries.push(HintEntry {
+                            keyspace,
+                            key,
+                            pos: new_pos.pos,
+                            len: new_pos.len,
+                            tombstone: true,
+                            expires_at: None,
+                            seq: record.seq,
+                        }),
+                    }
+                }
+            }
+        }
+        compaction_writer.flush()?;
+        compaction_writer.writer.get_ref().sync_all()?;
+        Ok(Some(Compacted {
+            moved,
+            hint_entries,
+            size: compaction_writer.pos,
+            cipher,
+        }))
+    }
+}
+
+/// Result of copying the live records of the victims.
+struct Compacted {
+    // keyspace, key, old position and new position of every copied set
+    moved: Vec<(u32, Vec<u8>, CommandPos, CommandPos)>,
+    hint_entries: Vec<HintEntry>,
+    // size of the new log file
+    size: u64,
+    // cipher of the new log file if it is encrypted
+    cipher: Option<Arc<FileCipher>>,
+}
+
+/// Removes the files of compactions that were interrupted by a crash.
+pub fn remove_interrupted(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension() == Some("compact".as_ref()) {
+            warn!(
+                "removing {:?} left behind by an interrupted compaction",
+                path
+            );
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+fn compaction_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.compact", gen))
+}
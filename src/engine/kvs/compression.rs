@@ -0,0 +1,114 @@
+//! Compression of the values stored in the log.
+//!
+//! Values at least `KvStoreOptions::compression_threshold` bytes long are
+//! compressed with the codec of the store, and the codec is recorded in the
+//! type of every set record, so a log written with several codecs over time
+//! can always be read back.
+
+use std::borrow::Cow;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+
+/// Codec of the values written to the log, set with
+/// `KvStoreOptions::compression`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Compression {
+    /// Values are stored as they are.
+    None = 0,
+    /// DEFLATE, which compresses text well at a moderate speed.
+    Deflate = 1,
+    /// Snappy, which compresses less but is several times faster.
+    Snappy = 2,
+}
+
+impl Compression {
+    pub(super) fn from_u8(b: u8) -> Option<Compression> {
+        match b {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Deflate),
+            2 => Some(Compression::Snappy),
+            _ => None,
+        }
+    }
+}
+
+/// Compresses the values written by the writer and the compactor, counting
+/// the bytes before and after compression.
+pub struct Compressor {
+    compression: Compression,
+    threshold: usize,
+    // bytes of the values written, before and after compression
+    raw_bytes: AtomicU64,
+    stored_bytes: AtomicU64,
+}
+
+impl Compressor {
+    pub fn new(compression: Compression, threshold: usize) -> Compressor {
+        Compressor {
+            compression,
+            threshold,
+            raw_bytes: AtomicU64::new(0),
+            stored_bytes: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the codec `value` is stored with and the stored bytes.
+    ///
+    /// A value shorter than the threshold, or that compression would not
+    /// shrink, is stored as it is.
+    pub fn compress<'a>(&self, value: &'a [u8]) -> (Compression, Cow<'a, [u8]>) {
+        let compressed = if value.len() < self.threshold {
+            None
+        } else {
+            match self.compression {
+                Compression::None => None,
+                Compression::Deflate => deflate(value),
+                Compression::Snappy => snap::raw::Encoder::new().compress_vec(value).ok(),
+            }
+        };
+        let (codec, stored) = match compressed {
+            Some(compressed) if compressed.len() < value.len() => {
+                (self.compression, Cow::Owned(compressed))
+            }
+            _ => (Compression::None, Cow::Borrowed(value)),
+        };
+        self.raw_bytes
+            .fetch_add(value.len() as u64, Ordering::Relaxed);
+        self.stored_bytes
+            .fetch_add(stored.len() as u64, Ordering::Relaxed);
+        (codec, stored)
+    }
+
+    /// Returns the bytes of the values written, before and after compression.
+    pub fn totals(&self) -> (u64, u64) {
+        (
+            self.raw_bytes.load(Ordering::Relaxed),
+            self.stored_bytes.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Decompresses a value stored with `codec`.
+///
+/// Returns `None` if the data is not valid for the codec.
+pub fn decompress(codec: Compression, data: &[u8]) -> Option<Vec<u8>> {
+    match codec {
+        Compression::None => Some(data.to_vec()),
+        Compression::Deflate => {
+            let mut value = Vec::new();
+            DeflateDecoder::new(data).read_to_end(&mut value).ok()?;
+            Some(value)
+        }
+        Compression::Snappy => snap::raw::Decoder::new().decompress_vec(data).ok(),
+    }
+}
+
+fn deflate(value: &[u8]) -> Option<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(value).ok()?;
+    encoder.finish().ok()
+}
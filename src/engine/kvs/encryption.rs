@@ -0,0 +1,187 @@
+//! Authenticated encryption of the log records.
+//!
+//! A log file written with a key starts with a random 16-byte nonce, and the
+//! payload of every set and remove record in it is sealed with
+//! XChaCha20-Poly1305 under the nonce of the file followed by the position of
+//! the record in the file, which no other record of the file shares. The
+//! record module picks the data authenticated along with the payload: the
+//! type and the sequence number of the record and the generation of the file. Lengths, checksums, record types and
+//! sequence numbers stay in the clear, so a torn write is still told apart
+//! from a wrong key.
+//!
+//! The file header also holds a tag sealing nothing at position `u64::MAX`,
+//! which tells which of the given keys the file was written with, if any.
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+
+use crate::{KvsError, Result};
+
+/// Length of the nonce of a file.
+pub const FILE_NONCE_LEN: usize = 16;
+
+/// Length of the tag appended to every sealed payload.
+pub const TAG_LEN: usize = 16;
+
+/// Position of the tag checking the key of a file.
+const CHECK_POS: u64 = u64::MAX;
+
+/// Position the hint file of a log is sealed at, which no record can start at.
+pub const HINT_POS: u64 = u64::MAX - 1;
+
+/// 256-bit key encrypting the log files, set with
+/// `KvStoreOptions::encryption_key`.
+///
+/// ```rust,no_run
+/// # use kvs::{EncryptionKey, KvStore, KvStoreOptions, Result};
+/// # fn try_main() -> Result<()> {
+/// let key = EncryptionKey::from_env("KVS_ENCRYPTION_KEY")?;
+/// let options = KvStoreOptions::new().encryption_key(key);
+/// let store = KvStore::open_with("data", options)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, PartialEq, Eq)]
+pub struct EncryptionKey([u8; 32]);
+
+impl EncryptionKey {
+    /// Creates a key from its raw bytes.
+    pub fn new(key: [u8; 32]) -> EncryptionKey {
+        EncryptionKey(key)
+    }
+
+    /// Parses a key written as 64 hexadecimal digits.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::InvalidOption` if `hex` is not a valid key.
+    pub fn from_hex(hex: &str) -> Result<EncryptionKey> {
+        let invalid = || KvsError::InvalidOption("a key must be 64 hex digits".to_owned());
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err(invalid());
+        }
+        let mut key = [0; 32];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).map_err(|_| invalid())?;
+        }
+        Ok(EncryptionKey(key))
+    }
+
+    /// Reads a key from a file holding either its 32 raw bytes or 64
+    /// hexadecimal digits, surrounding whitespace aside.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during reading the file, and returns
+    /// `KvsError::InvalidOption` if the file does not hold a valid key.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<EncryptionKey> {
+        let bytes = fs::read(path)?;
+        if let Ok(key) = <[u8; 32]>::try_from(bytes.as_slice()) {
+            return Ok(EncryptionKey(key));
+        }
+        match std::str::from_utf8(&bytes) {
+            Ok(hex) => EncryptionKey::from_hex(hex.trim()),
+            Err(_) => Err(KvsError::InvalidOption(
+                "a key file must hold 32 bytes or 64 hex digits".to_owned(),
+            )),
+        }
+    }
+
+    /// Reads a key written as 64 hexadecimal digits from the environment
+    /// variable `var`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::InvalidOption` if the variable is not set or does
+    /// not hold a valid key.
+    pub fn from_env(var: &str) -> Result<EncryptionKey> {
+        match std::env::var(var) {
+            Ok(hex) => EncryptionKey::from_hex(hex.trim()),
+            Err(_) => Err(KvsError::InvalidOption(format!(
+                "environment variable {} does not hold a key",
+                var
+            ))),
+        }
+    }
+}
+
+// keeps the key out of logs and panic messages
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+/// Seals and opens the records of one log file.
+pub struct FileCipher {
+    aead: XChaCha20Poly1305,
+    nonce: [u8; FILE_NONCE_LEN],
+}
+
+impl FileCipher {
+    /// Creates the cipher of a new log file, with a random nonce.
+    pub fn generate(key: &EncryptionKey) -> FileCipher {
+        let random = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let mut nonce = [0; FILE_NONCE_LEN];
+        nonce.copy_from_slice(&random[..FILE_NONCE_LEN]);
+        FileCipher {
+            aead: XChaCha20Poly1305::new(&key.0.into()),
+            nonce,
+        }
+    }
+
+    /// Returns the cipher of an existing log file with the given nonce and
+    /// check tag, trying each of `keys`.
+    ///
+    /// Returns `None` if the file was written with none of them.
+    pub fn find(
+        keys: &[EncryptionKey],
+        nonce: [u8; FILE_NONCE_LEN],
+        check: &[u8],
+    ) -> Option<FileCipher> {
+        keys.iter()
+            .map(|key| FileCipher {
+                aead: XChaCha20Poly1305::new(&key.0.into()),
+                nonce,
+            })
+            .find(|cipher| cipher.open(CHECK_POS, &[0], check).is_some())
+    }
+
+    /// Returns the nonce of the file.
+    pub fn nonce(&self) -> [u8; FILE_NONCE_LEN] {
+        self.nonce
+    }
+
+    /// Returns the tag checking the key of the file.
+    pub fn check(&self) -> Vec<u8> {
+        self.seal(CHECK_POS, &[0], &[])
+    }
+
+    /// Seals `payload` at `pos`, authenticating `aad` along with it.
+    pub fn seal(&self, pos: u64, aad: &[u8], payload: &[u8]) -> Vec<u8> {
+        let payload = Payload { msg: payload, aad };
+        self.aead
+            .encrypt(&self.record_nonce(pos), payload)
+            .expect("payload too long to encrypt")
+    }
+
+    /// Opens the payload sealed at `pos` along with `aad`.
+    ///
+    /// Returns `None` if the payload was not sealed there with this key and
+    /// this `aad`.
+    pub fn open(&self, pos: u64, aad: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+        let payload = Payload { msg: sealed, aad };
+        self.aead.decrypt(&self.record_nonce(pos), payload).ok()
+    }
+
+    fn record_nonce(&self, pos: u64) -> XNonce {
+        let mut nonce = XNonce::default();
+        nonce[..FILE_NONCE_LEN].copy_from_slice(&self.nonce);
+        nonce[FILE_NONCE_LEN..].copy_from_slice(&pos.to_le_bytes());
+        nonce
+    }
+}
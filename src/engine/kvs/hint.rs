@@ -0,0 +1,189 @@
+//! Hint files for fast startup.
+//!
+//! A hint file `<gen>.hint` sits next to the log file `<gen>.log` and lists the
+//! keyspace, key, offset, length, kind, expiry and sequence number of every
+//! record in that log, without the values. Loading the index from a hint file
+//! gives the same result as replaying its log, at a fraction of the I/O. Hint
+//! files are only written for compaction generations, which are never
+//! appended to once they are complete. A compaction generation only holds the
+//! tombstones still needed to hide older records of a key.
+//!
+//! ```text
+//! +-------------+-----------------+-----------------+-----------+
+//! | file header | entry | entry | ...               | crc (u32) |
+//! +-------------+-----------------+-----------------+-----------+
+//!
+//! entry: | key_len (u32) | pos (u64) | len (u64) | tombstone (u8) | expires_at (u64) | seq (u64) | keyspace (u32) | key (key_len) |
+//! ```
+//!
+//! An `expires_at` of zero means that the key does not expire.
+//!
+//! The trailing checksum covers all entries. A hint file that is missing, torn
+//! or damaged is ignored and its log is replayed instead.
+//!
+//! The entries of the hint of an encrypted log are sealed as a whole with the
+//! cipher of the log, and the checksum covers the sealed entries.
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use log::warn;
+
+use super::encryption::{FileCipher, HINT_POS};
+use crate::Result;
+
+/// Magic number at the start of every hint file.
+const MAGIC: [u8; 4] = *b"KVSH";
+
+/// Version of the hint file format.
+const VERSION: u32 = 1;
+
+/// Length of an entry without its key.
+const ENTRY_HEADER_LEN: usize = 41;
+
+/// Location of one record of the hinted log.
+pub struct HintEntry {
+    // id of the keyspace of the key
+    pub keyspace: u32,
+    pub key: Vec<u8>,
+    pub pos: u64,
+    pub len: u64,
+    // whether the record removes the key
+    pub tombstone: bool,
+    // expiry time of a set, in milliseconds since the Unix epoch
+    pub expires_at: Option<u64>,
+    // sequence number of the write
+    pub seq: u64,
+}
+
+/// Writes the hint file of generation `gen`, whose log is encrypted with
+/// `cipher` if any.
+///
+/// The file is written under a temporary name and renamed into place once it is
+/// complete, so a crash never leaves a partial hint file behind.
+pub fn write_hint(
+    dir: &Path,
+    gen: u64,
+    entries: &[HintEntry],
+    cipher: Option<&FileCipher>,
+) -> Result<()> {
+    let tmp_path = dir.join(format!("{}.hint.tmp", gen));
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&VERSION.to_le_bytes())?;
+
+    let mut body = Vec::new();
+    for entry in entries {
+        body.extend_from_slice(&(entry.key.len() as u32).to_le_bytes());
+        body.extend_from_slice(&entry.pos.to_le_bytes());
+        body.extend_from_slice(&entry.len.to_le_bytes());
+        body.push(entry.tombstone as u8);
+        body.extend_from_slice(&entry.expires_at.unwrap_or(0).to_le_bytes());
+        body.extend_from_slice(&entry.seq.to_le_bytes());
+        body.extend_from_slice(&entry.keyspace.to_le_bytes());
+        body.extend_from_slice(&entry.key);
+    }
+    if let Some(cipher) = cipher {
+        body = cipher.seal(HINT_POS, &[0], &body);
+    }
+    writer.write_all(&body)?;
+    writer.write_all(&crc32fast::hash(&body).to_le_bytes())?;
+    writer
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .sync_all()?;
+
+    fs::rename(&tmp_path, hint_path(dir, gen))?;
+    Ok(())
+}
+
+/// Reads the hint file of generation `gen`, whose log is encrypted with
+/// `cipher` if any.
+///
+/// Returns `None` if there is no hint file or if it is not valid.
+pub fn read_hint(
+    dir: &Path,
+    gen: u64,
+    cipher: Option<&FileCipher>,
+) -> Result<Option<Vec<HintEntry>>> {
+    let path = hint_path(dir, gen);
+    let mut buf = Vec::new();
+    match File::open(&path) {
+        Ok(mut file) => file.read_to_end(&mut buf)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let entries = parse(&buf, cipher);
+    if entries.is_none() {
+        warn!("{:?} is damaged, replaying its log instead", path);
+    }
+    Ok(entries)
+}
+
+/// Removes the hint file of generation `gen` if there is one.
+pub fn remove_hint(dir: &Path, gen: u64) -> io::Result<()> {
+    match fs::remove_file(hint_path(dir, gen)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn parse(buf: &[u8], cipher: Option<&FileCipher>) -> Option<Vec<HintEntry>> {
+    if buf.len() < 12 || buf[..4] != MAGIC || buf[4..8] != VERSION.to_le_bytes() {
+        return None;
+    }
+    let (body, crc) = buf[8..].split_at(buf.len() - 12);
+    if crc32fast::hash(body).to_le_bytes() != crc {
+        return None;
+    }
+    let opened;
+    let mut body = match cipher {
+        Some(cipher) => {
+            opened = cipher.open(HINT_POS, &[0], body)?;
+            opened.as_slice()
+        }
+        None => body,
+    };
+
+    let mut entries = Vec::new();
+    while !body.is_empty() {
+        if body.len() < ENTRY_HEADER_LEN {
+            return None;
+        }
+        let key_len = u32::from_le_bytes(body[..4].try_into().unwrap()) as usize;
+        let pos = u64::from_le_bytes(body[4..12].try_into().unwrap());
+        let len = u64::from_le_bytes(body[12..20].try_into().unwrap());
+        let tombstone = match body[20] {
+            0 => false,
+            1 => true,
+            _ => return None,
+        };
+        let expires_at = match u64::from_le_bytes(body[21..29].try_into().unwrap()) {
+            0 => None,
+            expires_at => Some(expires_at),
+        };
+        let seq = u64::from_le_bytes(body[29..37].try_into().unwrap());
+        let keyspace = u32::from_le_bytes(body[37..41].try_into().unwrap());
+        let end = ENTRY_HEADER_LEN + key_len;
+        if body.len() < end {
+            return None;
+        }
+        let key = body[ENTRY_HEADER_LEN..end].to_vec();
+        entries.push(HintEntry {
+            keyspace,
+            key,
+            pos,
+            len,
+            tombstone,
+            expires_at,
+            seq,
+        });
+        body = &body[end..];
+    }
+    Some(entries)
+}
+
+pub fn hint_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.hint", gen))
+}
@@ -0,0 +1,100 @@
+//! Named keyspaces of a store.
+//!
+//! Every keyspace has its own index and a numeric id, which the records of its
+//! commands carry in the shared log. The default keyspace, the one `KvStore`
+//! opens, has id 0 and no name.
+//!
+//! The names and ids of the other keyspaces are kept in the `keyspaces` file
+//! of the store directory as JSON, which is rewritten under a temporary name
+//! and renamed into place on every change. The names are not encrypted, even
+//! if the log is. Ids are never reused: the records of a dropped keyspace stay
+//! in the log until a compaction drops them, and must never be read back as
+//! the records of a newer keyspace.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::sync_dir;
+use crate::Result;
+
+/// Id of the default keyspace.
+pub const DEFAULT_KEYSPACE: u32 = 0;
+
+/// Name of the file listing the keyspaces.
+const REGISTRY_FILE: &str = "keyspaces";
+
+/// Names and ids of the named keyspaces of a store.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Registry {
+    // id of the next keyspace created
+    next_id: u32,
+    keyspaces: BTreeMap<String, u32>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Registry {
+            next_id: DEFAULT_KEYSPACE + 1,
+            keyspaces: BTreeMap::new(),
+        }
+    }
+}
+
+impl Registry {
+    /// Reads the registry of the store in `dir`, which is empty if no keyspace
+    /// was ever created.
+    pub fn load(dir: &Path) -> Result<Registry> {
+        match fs::read(registry_path(dir)) {
+            Ok(buf) => Ok(serde_json::from_slice(&buf)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Registry::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Writes the registry to the store in `dir` and syncs it.
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        let tmp_path = dir.join(format!("{}.tmp", REGISTRY_FILE));
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&serde_json::to_vec(self)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, registry_path(dir))?;
+        sync_dir(dir)
+    }
+
+    /// Returns the id of the keyspace `name`.
+    pub fn get(&self, name: &str) -> Option<u32> {
+        self.keyspaces.get(name).copied()
+    }
+
+    /// Returns the ids of all the named keyspaces.
+    pub fn ids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.keyspaces.values().copied()
+    }
+
+    /// Returns the names of all the named keyspaces, in order.
+    pub fn names(&self) -> Vec<String> {
+        self.keyspaces.keys().cloned().collect()
+    }
+
+    /// Adds the keyspace `name` with a fresh id, which is returned.
+    pub fn create(&mut self, name: &str) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.keyspaces.insert(name.to_owned(), id);
+        id
+    }
+
+    /// Removes the keyspace `name`, returning its id.
+    pub fn remove(&mut self, name: &str) -> Option<u32> {
+        self.keyspaces.remove(name)
+    }
+}
+
+/// Returns the path of the registry of the store in `dir`.
+pub fn registry_path(dir: &Path) -> PathBuf {
+    dir.join(REGISTRY_FILE)
+}
@@ -0,0 +1,119 @@
+//! Upgrade of the stores written by the first release, whose log files are
+//! sequences of serde_json commands instead of binary records.
+//!
+//! When a store is opened, the JSON log files are renamed from `<gen>.log` to
+//! `<gen>.legacy`, out of the way of the binary generations. Once the store is
+//! open, the live pairs of the JSON logs are written to it, synced, and the
+//! JSON logs deleted. Rewriting the pairs is idempotent, so an upgrade
+//! interrupted by a crash is finished by the next open.
+
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::Deserializer;
+
+use super::{log_path, sync_dir, KvStore};
+use crate::{KvsEngine, KvsError, Result, WriteBatch};
+
+/// Number of pairs written by each batch of an upgrade.
+const BATCH_SIZE: usize = 1000;
+
+/// Command of a JSON log file.
+#[derive(Deserialize)]
+enum LegacyCommand {
+    Set { key: String, value: String },
+    Remove { key: String },
+}
+
+/// Renames the JSON log files in `dir` to `<gen>.legacy`.
+///
+/// Returns the generations of all the JSON logs still to be replayed, in
+/// order, including those set aside by an interrupted upgrade.
+pub(super) fn set_aside(dir: &Path) -> Result<Vec<u64>> {
+    let mut renamed = false;
+    for gen in super::sorted_gen_list(dir)? {
+        let path = log_path(dir, gen);
+        let mut first = [0];
+        if File::open(&path)?.read(&mut first)? == 1 && first[0] == b'{' {
+            fs::rename(&path, legacy_path(dir, gen))?;
+            renamed = true;
+        }
+    }
+    if renamed {
+        sync_dir(dir)?;
+    }
+    let mut gens: Vec<u64> = fs::read_dir(dir)?
+        .flat_map(|res| -> Result<_> { Ok(res?.path()) })
+        .filter(|path| path.is_file() && path.extension() == Some("legacy".as_ref()))
+        .flat_map(|path| {
+            path.file_stem()
+                .and_then(OsStr::to_str)
+                .map(str::parse::<u64>)
+        })
+        .flatten()
+        .collect();
+    gens.sort_unstable();
+    Ok(gens)
+}
+
+/// Writes the live pairs of the JSON logs of generations `gens` to `store`,
+/// then deletes the JSON logs.
+pub(super) fn replay(store: &KvStore, dir: &Path, gens: &[u64]) -> Result<()> {
+    let mut pairs = BTreeMap::new();
+    for &gen in gens {
+        let reader = BufReader::new(File::open(legacy_path(dir, gen))?);
+        let mut stream = Deserializer::from_reader(reader).into_iter::<LegacyCommand>();
+        loop {
+            let pos = stream.byte_offset() as u64;
+            match stream.next() {
+                Some(Ok(LegacyCommand::Set { key, value })) => {
+                    pairs.insert(key, value);
+                }
+                Some(Ok(LegacyCommand::Remove { key })) => {
+                    pairs.remove(&key);
+                }
+                // the first release did not sync, so a crash can cut off the
+                // last command of the newest log
+                Some(Err(e)) if e.is_eof() && Some(&gen) == gens.last() => {
+                    warn!(
+                        "generation {}: dropped an incomplete command at offset {}",
+                        gen, pos
+                    );
+                    break;
+                }
+                Some(Err(_)) => return Err(KvsError::Corruption { gen, pos }),
+                None => break,
+            }
+        }
+    }
+
+    let count = pairs.len();
+    let mut batch = WriteBatch::new();
+    for (key, value) in pairs {
+        batch.set(key, value);
+        if batch.len() >= BATCH_SIZE {
+            store.write_batch(std::mem::take(&mut batch))?;
+        }
+    }
+    store.write_batch(batch)?;
+    store.writer.lock().unwrap().sync()?;
+
+    for &gen in gens {
+        fs::remove_file(legacy_path(dir, gen))?;
+    }
+    sync_dir(dir)?;
+    info!(
+        "upgraded {} pairs from the JSON log files of {:?}",
+        count, dir
+    );
+    Ok(())
+}
+
+fn legacy_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.legacy", gen))
+}
@@ -0,0 +1,352 @@
+use std::time::Duration;
+
+use super::{Compression, EncryptionKey};
+use crate::{Durability, KvsError, Result};
+
+/// Default number of stale bytes that triggers a compaction.
+const DEFAULT_COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+
+/// Default fraction of stale bytes at which a log file is compacted.
+const DEFAULT_SEGMENT_STALE_RATIO: f64 = 0.5;
+
+/// Default memory budget of the read cache.
+const DEFAULT_CACHE_SIZE: usize = 8 * 1024 * 1024;
+
+/// Default interval between two removals of the expired keys.
+const DEFAULT_REAP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Default size from which values are compressed.
+const DEFAULT_COMPRESSION_THRESHOLD: usize = 512;
+
+/// Default size of the read and write buffers, the same as `std::io::BufReader`.
+const DEFAULT_BUFFER_SIZE: usize = 8 * 1024;
+
+/// Options for opening a `KvStore` with `KvStore::open_with`.
+///
+/// The defaults are the ones used by `KvStore::open`. Options are set with
+/// chained builder methods:
+///
+/// ```rust
+/// # use kvs::{CompactionTrigger, Durability, KvStore, KvStoreOptions, Result};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// let options = KvStoreOptions::new()
+///     .compaction_trigger(CompactionTrigger::StaleRatio(0.5))
+///     .max_segment_size(64 * 1024 * 1024)
+///     .durability(Durability::Always);
+/// let store = KvStore::open_with(current_dir()?, options)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct KvStoreOptions {
+    pub(super) compaction_trigger: CompactionTrigger,
+    pub(super) segment_stale_ratio: f64,
+    pub(super) max_segment_size: Option<u64>,
+    pub(super) compaction_rate_limit: Option<u64>,
+    pub(super) cache_size: usize,
+    pub(super) mmap_reads: bool,
+    pub(super) reap_interval: Duration,
+    pub(super) read_buffer_size: usize,
+    pub(super) write_buffer_size: usize,
+    pub(super) durability: Durability,
+    pub(super) create_if_missing: bool,
+    pub(super) error_if_exists: bool,
+    pub(super) recovery_mode: RecoveryMode,
+    pub(super) compression: Compression,
+    pub(super) compression_threshold: usize,
+    pub(super) encryption_key: Option<EncryptionKey>,
+    pub(super) old_encryption_keys: Vec<EncryptionKey>,
+}
+
+/// When a compaction runs.
+///
+/// The trigger looks at the stale commands of the whole store, while the log
+/// files actually compacted are picked with `KvStoreOptions::segment_stale_ratio`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompactionTrigger {
+    /// Compact once the stale commands take more than the given number of bytes.
+    StaleBytes(u64),
+    /// Compact once the stale commands take more than the given fraction of the
+    /// bytes taken by live entries. To keep small stores from compacting on
+    /// every few writes, at least 64 KiB must be stale.
+    StaleRatio(f64),
+}
+
+/// Minimum number of stale bytes before `CompactionTrigger::StaleRatio` starts a
+/// compaction.
+pub(super) const MIN_RATIO_COMPACTION_BYTES: u64 = 64 * 1024;
+
+/// How `KvStore::open_with` handles an incomplete record at the end of the
+/// newest log, which is what a crash in the middle of a write leaves behind.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecoveryMode {
+    /// Truncate the log back to the last complete record, log what was dropped
+    /// and continue opening the store.
+    TruncateTail,
+    /// Refuse to open the store with `KvsError::IncompleteRecord`, leaving the
+    /// log untouched for inspection.
+    Strict,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            compaction_trigger: CompactionTrigger::StaleBytes(DEFAULT_COMPACTION_THRESHOLD),
+            segment_stale_ratio: DEFAULT_SEGMENT_STALE_RATIO,
+            max_segment_size: None,
+            compaction_rate_limit: None,
+            cache_size: DEFAULT_CACHE_SIZE,
+            mmap_reads: false,
+            reap_interval: DEFAULT_REAP_INTERVAL,
+            read_buffer_size: DEFAULT_BUFFER_SIZE,
+            write_buffer_size: DEFAULT_BUFFER_SIZE,
+            durability: Durability::Never,
+            create_if_missing: true,
+            error_if_exists: false,
+            recovery_mode: RecoveryMode::TruncateTail,
+            compression: Compression::None,
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            encryption_key: None,
+            old_encryption_keys: Vec::new(),
+        }
+    }
+}
+
+impl KvStoreOptions {
+    /// Creates the default options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets when a compaction runs.
+    ///
+    /// Defaults to `CompactionTrigger::StaleBytes(1 MiB)`.
+    pub fn compaction_trigger(mut self, trigger: CompactionTrigger) -> Self {
+        self.compaction_trigger = trigger;
+        self
+    }
+
+    /// Sets the fraction of stale bytes at which a log file is compacted when a
+    /// compaction runs.
+    ///
+    /// Only the live entries of these files are copied, so the cost of a
+    /// compaction grows with the garbage it collects rather than with the size
+    /// of the store. Must be in `(0, 1]`, defaults to `0.5`.
+    pub fn segment_stale_ratio(mut self, ratio: f64) -> Self {
+        self.segment_stale_ratio = ratio;
+        self
+    }
+
+    /// Sets the size in bytes at which the active log file is closed and writes
+    /// move on to a new generation.
+    ///
+    /// A log file can exceed the size by the last batch of writes appended to
+    /// it. The file written by a compaction holds all live entries and is not
+    /// split. Unlimited by default.
+    pub fn max_segment_size(mut self, size: u64) -> Self {
+        self.max_segment_size = Some(size);
+        self
+    }
+
+    /// Sets the number of bytes per second a compaction may read and write, so
+    /// that it leaves most of the disk to gets and sets.
+    ///
+    /// It can be changed on an open store with
+    /// `KvStore::set_compaction_rate_limit`. Unlimited by default.
+    pub fn compaction_rate_limit(mut self, bytes_per_sec: u64) -> Self {
+        self.compaction_rate_limit = Some(bytes_per_sec);
+        self
+    }
+
+    /// Sets the memory budget in bytes of the cache of values read from the log
+    /// files, shared by all clones of the store. Zero disables the cache.
+    ///
+    /// Defaults to 8 MiB.
+    pub fn cache_size(mut self, size: usize) -> Self {
+        self.cache_size = size;
+        self
+    }
+
+    /// Sets whether values in sealed log files are read through memory maps
+    /// instead of buffered reads, which saves a seek and a read syscall per
+    /// get. The active log file is always read with buffered reads.
+    ///
+    /// Off by default.
+    pub fn mmap_reads(mut self, mmap: bool) -> Self {
+        self.mmap_reads = mmap;
+        self
+    }
+
+    /// Sets how often a background thread appends a remove for every key whose
+    /// time to live has passed.
+    ///
+    /// Reads stop returning a key as soon as it expires, so this only bounds
+    /// how long an expired value stays in the log. Defaults to one second.
+    pub fn reap_interval(mut self, interval: Duration) -> Self {
+        self.reap_interval = interval;
+        self
+    }
+
+    /// Sets the buffer size used to read whole log files, when the store is
+    /// opened and when it is compacted. Gets read single records and are not
+    /// buffered.
+    pub fn read_buffer_size(mut self, size: usize) -> Self {
+        self.read_buffer_size = size;
+        self
+    }
+
+    /// Sets the buffer size of the writers of the log files.
+    pub fn write_buffer_size(mut self, size: usize) -> Self {
+        self.write_buffer_size = size;
+        self
+    }
+
+    /// Sets when writes are synced to disk.
+    ///
+    /// Every write is flushed to the OS before it is acknowledged, so it
+    /// survives a crash of the process in every mode. What survives a power
+    /// failure depends on the mode:
+    ///
+    /// - `Durability::Always`: every acknowledged write.
+    /// - `Durability::Interval(d)`: every write acknowledged more than `d`
+    ///   before the failure.
+    /// - `Durability::Never`: whatever the OS has written back on its own, and
+    ///   every log file but the active one, which is synced when the store
+    ///   moves on to a new one.
+    ///
+    /// Concurrent writers are committed in batches, so a single flush and sync
+    /// acknowledges every write that queued up while the previous batch was
+    /// written. Defaults to `Durability::Never`.
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Sets whether the store directory is created if it does not exist.
+    ///
+    /// On by default.
+    pub fn create_if_missing(mut self, create: bool) -> Self {
+        self.create_if_missing = create;
+        self
+    }
+
+    /// Sets whether opening fails if the directory already holds a store.
+    ///
+    /// Off by default.
+    pub fn error_if_exists(mut self, error: bool) -> Self {
+        self.error_if_exists = error;
+        self
+    }
+
+    /// Sets how an incomplete record at the end of the newest log is handled.
+    ///
+    /// Defaults to `RecoveryMode::TruncateTail`.
+    pub fn recovery_mode(mut self, mode: RecoveryMode) -> Self {
+        self.recovery_mode = mode;
+        self
+    }
+
+    /// Sets the codec values are compressed with when they are written,
+    /// including when a compaction copies them.
+    ///
+    /// Values already in the log keep their codec until a compaction rewrites
+    /// them, so opening a store with a different codec and calling
+    /// `KvStore::compact` recompresses all of them. Defaults to
+    /// `Compression::None`.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Sets the size in bytes from which values are compressed. Smaller values
+    /// are stored as they are.
+    ///
+    /// Defaults to 512 bytes.
+    pub fn compression_threshold(mut self, size: usize) -> Self {
+        self.compression_threshold = size;
+        self
+    }
+
+    /// Sets the key the log files and hint files written from now on are
+    /// encrypted with.
+    ///
+    /// Files written in the clear stay readable, and a compaction rewrites
+    /// them encrypted. Opening a store with encrypted files fails with
+    /// `KvsError::WrongKey` unless their key is given. Off by default.
+    pub fn encryption_key(mut self, key: EncryptionKey) -> Self {
+        self.encryption_key = Some(key);
+        self
+    }
+
+    /// Adds a key that older log files may be encrypted with, which is only
+    /// used to read them.
+    ///
+    /// To rotate the key, open the store with the new key as the
+    /// `encryption_key` and the old one here, and call `KvStore::compact`,
+    /// which rewrites every log file with the new key.
+    pub fn old_encryption_key(mut self, key: EncryptionKey) -> Self {
+        self.old_encryption_keys.push(key);
+        self
+    }
+
+    /// Returns every key log files may be encrypted with.
+    pub(super) fn decryption_keys(&self) -> Vec<EncryptionKey> {
+        self.encryption_key
+            .iter()
+            .chain(&self.old_encryption_keys)
+            .cloned()
+            .collect()
+    }
+
+    /// Checks that the options make sense together.
+    pub(super) fn validate(&self) -> Result<()> {
+        if let CompactionTrigger::StaleRatio(ratio) = self.compaction_trigger {
+            if ratio.is_nan() || ratio <= 0.0 {
+                return Err(KvsError::InvalidOption(format!(
+                    "compaction ratio must be positive, got {}",
+                    ratio
+                )));
+            }
+        }
+        let ratio = self.segment_stale_ratio;
+        if ratio.is_nan() || ratio <= 0.0 || ratio > 1.0 {
+            return Err(KvsError::InvalidOption(format!(
+                "segment stale ratio must be in (0, 1], got {}",
+                ratio
+            )));
+        }
+        if self.max_segment_size == Some(0) {
+            return Err(KvsError::InvalidOption(
+                "maximum segment size must be positive".to_owned(),
+            ));
+        }
+        check_rate_limit(self.compaction_rate_limit)?;
+        if self.durability == Durability::Interval(Duration::from_secs(0)) {
+            return Err(KvsError::InvalidOption(
+                "sync interval must be positive".to_owned(),
+            ));
+        }
+        if self.reap_interval == Duration::from_secs(0) {
+            return Err(KvsError::InvalidOption(
+                "reap interval must be positive".to_owned(),
+            ));
+        }
+        if self.read_buffer_size == 0 || self.write_buffer_size == 0 {
+            return Err(KvsError::InvalidOption(
+                "buffer sizes must be positive".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Checks a compaction rate limit, where `None` means unlimited.
+pub(super) fn check_rate_limit(bytes_per_sec: Option<u64>) -> Result<()> {
+    if bytes_per_sec == Some(0) {
+        return Err(KvsError::InvalidOption(
+            "compaction rate limit must be positive".to_owned(),
+        ));
+    }
+    Ok(())
+}
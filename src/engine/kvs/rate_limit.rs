@@ -0,0 +1,72 @@
+//! Token bucket limiting the I/O of background work.
+//!
+//! The bucket fills up at the configured number of bytes per second and holds
+//! at most one second worth of bytes. A request is let through as soon as the
+//! bucket is not empty and may take it below zero, so requests larger than the
+//! bucket do not block forever; the next request then waits until the debt is
+//! paid back.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Longest sleep between two checks of the rate and of the shutdown flag, so
+/// that a new rate or a shutdown is picked up quickly.
+const MAX_WAIT: Duration = Duration::from_millis(100);
+
+pub struct RateLimiter {
+    // zero means unlimited
+    bytes_per_sec: AtomicU64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: Option<u64>) -> RateLimiter {
+        let rate = bytes_per_sec.unwrap_or(0);
+        RateLimiter {
+            bytes_per_sec: AtomicU64::new(rate),
+            bucket: Mutex::new(Bucket {
+                tokens: rate as f64,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Sets the number of bytes per second, or lifts the limit with `None`.
+    pub fn set_rate(&self, bytes_per_sec: Option<u64>) {
+        self.bytes_per_sec
+            .store(bytes_per_sec.unwrap_or(0), Ordering::SeqCst);
+    }
+
+    /// Waits until `bytes` can be read or written.
+    ///
+    /// Returns early if `shutdown` is set.
+    pub fn acquire(&self, bytes: u64, shutdown: &AtomicBool) {
+        loop {
+            let rate = self.bytes_per_sec.load(Ordering::SeqCst);
+            if rate == 0 || shutdown.load(Ordering::SeqCst) {
+                return;
+            }
+            let rate = rate as f64;
+            let wait = {
+                let mut bucket = self.bucket.lock().unwrap();
+                let now = Instant::now();
+                let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+                bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+                bucket.last_refill = now;
+                if bucket.tokens > 0.0 {
+                    bucket.tokens -= bytes as f64;
+                    return;
+                }
+                Duration::from_secs_f64(-bucket.tokens / rate)
+            };
+            thread::sleep(wait.min(MAX_WAIT));
+        }
+    }
+}
@@ -0,0 +1,528 @@
+//! Binary record format of the `.log` generations.
+//!
+//! Every log file starts with a file header made of a magic number, the
+//! format version and the cipher of the file, 0 for none and 1 for
+//! XChaCha20-Poly1305, followed by the nonce and the key check tag of an
+//! encrypted file, see the `encryption` module. The records are:
+//!
+//! ```text
+//! +-----------+-----------+------------+-----------------+
+//! | crc (u32) | len (u32) | type (u8)  | payload (len)   |
+//! +-----------+-----------+------------+-----------------+
+//! ```
+//!
+//! All integers are little-endian. The checksum is a CRC-32 of everything after
+//! the `crc` field, so a torn write or a flipped bit in either the header or the
+//! payload is detected before the payload is decoded.
+//!
+//! The high bit of the type marks a sequenced record, whose payload starts
+//! with the sequence number of the write as a `u64`. Every record but those
+//! inside a batch is sequenced.
+//!
+//! The payload of a set is the key length as a `u32`, the key and the value,
+//! and the payload of a remove is the key. Keys and values are arbitrary bytes.
+//!
+//! An expiring set starts its payload with the expiry time in milliseconds
+//! since the Unix epoch, before the fields of a plain set.
+//!
+//! Bits 5 and 6 of the type of a set hold the codec its value is compressed
+//! with, see `Compression`. The key is never compressed.
+//!
+//! Bit 4 of the type of a set or remove marks a command of a named keyspace, whose payload starts with the id of the keyspace as a
+//! `u32`, before the expiry time of an expiring set. Commands without it
+//! belong to the default keyspace.
+//!
+//! In an encrypted file, the payload of every set and remove, after the
+//! sequence number, is sealed with the cipher of the file and ends with its
+//! authentication tag. The checksum covers the sealed payload. The seal also
+//! authenticates the type byte, flags included, the sequence number of a
+//! sequenced record and the generation of the file, and the sequence number of
+//! an encrypted batch is followed by a tag sealing nothing that authenticates
+//! the same fields of the batch.
+//!
+//! The payload of a batch record is the complete set and remove records of the
+//! batch. The checksum of the batch covers all of them, so a batch cut off by a
+//! crash is dropped as a whole, and every inner record keeps its own checksum,
+//! so the index can point to it and read it on its own. The inner records share
+//! the sequence number of the batch.
+
+use std::borrow::Cow;
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::sync::Arc;
+
+use super::compression::{self, Compression, Compressor};
+use super::encryption::{EncryptionKey, FileCipher, FILE_NONCE_LEN, TAG_LEN};
+use super::keyspace::DEFAULT_KEYSPACE;
+use super::Command;
+use crate::{KvsError, Result};
+
+/// Magic number at the start of every log file.
+const MAGIC: [u8; 4] = *b"KVSL";
+
+/// Version of the log format.
+const VERSION: u32 = 1;
+
+/// Length of the magic number and version at the start of every file header.
+const FILE_HEADER_LEN: usize = 8;
+
+/// Cipher byte of the file header of a file written in the clear.
+const PLAIN: u8 = 0;
+
+/// Cipher byte of the file header of a file encrypted with XChaCha20-Poly1305.
+const XCHACHA20_POLY1305: u8 = 1;
+
+/// Length of the record header in bytes.
+const HEADER_LEN: usize = 9;
+
+/// Flag of the record type of a record starting with a sequence number.
+const SEQUENCED: u8 = 0x80;
+
+/// Bits of the record type holding the codec of the value of a set.
+const CODEC_MASK: u8 = 0x60;
+
+/// Position of the codec in the record type.
+const CODEC_SHIFT: u32 = 5;
+
+/// Flag of the record type of a command of a named keyspace.
+const KEYSPACE: u8 = 0x10;
+
+/// Commands of a record, each with the range of its own record.
+pub type Commands = Vec<(Command, Range<u64>)>;
+
+/// A record read from a log file.
+pub struct Record {
+    /// Sequence number of the write, shared by all the commands of a batch.
+    pub seq: u64,
+    pub cmds: Commands,
+    /// Length of the whole record.
+    pub len: u64,
+}
+
+/// Header of a log file.
+pub struct FileHeader {
+    /// Cipher of the records of an encrypted file.
+    pub cipher: Option<Arc<FileCipher>>,
+}
+
+/// How the records appended to a log file are encoded.
+#[derive(Clone, Copy)]
+pub struct Encoder<'a> {
+    pub compressor: &'a Compressor,
+    /// Cipher of the file, if it is encrypted.
+    pub cipher: Option<&'a FileCipher>,
+    /// Generation of the file, authenticated by the seals.
+    pub gen: u64,
+}
+
+/// Type of a record, stored right after the length in the record header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+enum RecordType {
+    Set = 1,
+    Remove = 2,
+    Batch = 3,
+    ExpiringSet = 4,
+}
+
+impl RecordType {
+    fn from_u8(b: u8) -> Option<RecordType> {
+        match b {
+            1 => Some(RecordType::Set),
+            2 => Some(RecordType::Remove),
+            3 => Some(RecordType::Batch),
+            4 => Some(RecordType::ExpiringSet),
+            _ => None,
+        }
+    }
+}
+
+/// Writes the file header of a new log file, encrypted with `cipher` if any.
+pub fn write_file_header<W: Write>(writer: &mut W, cipher: Option<&FileCipher>) -> io::Result<()> {
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&VERSION.to_le_bytes())?;
+    match cipher {
+        Some(cipher) => {
+            writer.write_all(&[XCHACHA20_POLY1305])?;
+            writer.write_all(&cipher.nonce())?;
+            writer.write_all(&cipher.check())
+        }
+        None => writer.write_all(&[PLAIN]),
+    }
+}
+
+/// Reads and checks the file header of the log file of generation `gen`,
+/// finding the key of an encrypted file among `keys`.
+///
+/// Returns `None` if the file is empty.
+///
+/// # Errors
+///
+/// It returns `KvsError::WrongKey` if the file is encrypted with none of
+/// `keys`.
+pub fn read_file_header<R: Read>(
+    reader: &mut R,
+    gen: u64,
+    keys: &[EncryptionKey],
+) -> Result<Option<FileHeader>> {
+    let mut header = [0; FILE_HEADER_LEN];
+    match read_full(reader, &mut header)? {
+        0 => return Ok(None),
+        n if n < header.len() => return Err(KvsError::IncompleteRecord { gen, pos: 0 }),
+        _ => {}
+    }
+    if header[..4] != MAGIC {
+        return Err(KvsError::Corruption { gen, pos: 0 });
+    }
+    let version = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    if version != VERSION {
+        return Err(KvsError::UnsupportedVersion(version));
+    }
+    let mut cipher = [0];
+    if read_full(reader, &mut cipher)? < 1 {
+        return Err(KvsError::IncompleteRecord { gen, pos: 0 });
+    }
+    match cipher[0] {
+        PLAIN => Ok(Some(FileHeader { cipher: None })),
+        XCHACHA20_POLY1305 => {
+            let mut nonce = [0; FILE_NONCE_LEN];
+            let mut check = [0; TAG_LEN];
+            if read_full(reader, &mut nonce)? < FILE_NONCE_LEN
+                || read_full(reader, &mut check)? < TAG_LEN
+            {
+                return Err(KvsError::IncompleteRecord { gen, pos: 0 });
+            }
+            match FileCipher::find(keys, nonce, &check) {
+                Some(cipher) => Ok(Some(FileHeader {
+                    cipher: Some(Arc::new(cipher)),
+                })),
+                None => Err(KvsError::WrongKey { gen }),
+            }
+        }
+        _ => Err(KvsError::Corruption { gen, pos: 0 }),
+    }
+}
+
+/// Encodes a command written with sequence number `seq` at position `pos` of
+/// its log file into a complete record, header included.
+pub fn encode(cmd: &Command, seq: u64, pos: u64, encoder: Encoder) -> Vec<u8> {
+    let (record_type, payload) = encode_payload(cmd, Some(seq), pos, encoder);
+    frame(record_type, Some(seq), &payload)
+}
+
+/// Encodes the payload of a set or remove record at position `pos`, with
+/// sequence number `seq` if the record is sequenced, sealed if the file is
+/// encrypted.
+///
+/// Returns the record type, with the codec of the value of a set, and the
+/// payload.
+fn encode_payload(cmd: &Command, seq: Option<u64>, pos: u64, encoder: Encoder) -> (u8, Vec<u8>) {
+    let (record_type, payload) = encode_plain_payload(cmd, encoder.compressor);
+    match encoder.cipher {
+        Some(cipher) => {
+            let aad = aad(record_type, seq, encoder.gen);
+            (record_type, cipher.seal(pos, &aad, &payload))
+        }
+        None => (record_type, payload),
+    }
+}
+
+/// Returns the data authenticated along with the payload of a record of type
+/// `record_type`, without the sequenced flag, with sequence number `seq` if
+/// the record is sequenced, in the encrypted log file of generation `gen`.
+fn aad(record_type: u8, seq: Option<u64>, gen: u64) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(17);
+    match seq {
+        Some(seq) => {
+            aad.push(record_type | SEQUENCED);
+            aad.extend_from_slice(&seq.to_le_bytes());
+        }
+        None => aad.push(record_type),
+    }
+    aad.extend_from_slice(&gen.to_le_bytes());
+    aad
+}
+
+fn encode_plain_payload(cmd: &Command, compressor: &Compressor) -> (u8, Vec<u8>) {
+    let mut payload = Vec::new();
+    let flag = match cmd.keyspace() {
+        DEFAULT_KEYSPACE => 0,
+        keyspace => {
+            payload.extend_from_slice(&keyspace.to_le_bytes());
+            KEYSPACE
+        }
+    };
+    match cmd {
+        Command::Set {
+            key,
+            value,
+            expires_at,
+            ..
+        } => {
+            payload.reserve(12 + key.len() + value.len());
+            let record_type = match expires_at {
+                Some(expires_at) => {
+                    payload.extend_from_slice(&expires_at.to_le_bytes());
+                    RecordType::ExpiringSet
+                }
+                None => RecordType::Set,
+            };
+            let (codec, value) = compressor.compress(value);
+            payload.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            payload.extend_from_slice(key);
+            payload.extend_from_slice(&value);
+            (
+                record_type as u8 | flag | (codec as u8) << CODEC_SHIFT,
+                payload,
+            )
+        }
+        Command::Remove { key, .. } => {
+            payload.extend_from_slice(key);
+            (RecordType::Remove as u8 | flag, payload)
+        }
+    }
+}
+
+/// Encodes the commands of a batch written with sequence number `seq` at
+/// position `pos` of its log file into a complete batch record.
+///
+/// Returns the record and the range of the inner record of every command,
+/// relative to the start of the batch record.
+pub fn encode_batch(
+    cmds: &[Command],
+    seq: u64,
+    pos: u64,
+    encoder: Encoder,
+) -> (Vec<u8>, Vec<Range<u64>>) {
+    // the sequence number in front of the inner records
+    let offset = HEADER_LEN + 8;
+    // the tag authenticating the header of the batch
+    let mut payload = match encoder.cipher {
+        Some(cipher) => {
+            let aad = aad(RecordType::Batch as u8, Some(seq), encoder.gen);
+            cipher.seal(pos, &aad, &[])
+        }
+        None => Vec::new(),
+    };
+    let mut ranges = Vec::with_capacity(cmds.len());
+    for cmd in cmds {
+        let start = (offset + payload.len()) as u64;
+        let (record_type, inner) = encode_payload(cmd, None, pos + start, encoder);
+        payload.extend_from_slice(&frame(record_type, None, &inner));
+        ranges.push(start..(offset + payload.len()) as u64);
+    }
+    (frame(RecordType::Batch as u8, Some(seq), &payload), ranges)
+}
+
+/// Prepends the record header, and the sequence number if any, to `payload`.
+fn frame(record_type: u8, seq: Option<u64>, payload: &[u8]) -> Vec<u8> {
+    let seq_len = if seq.is_some() { 8 } else { 0 };
+    let mut record = Vec::with_capacity(HEADER_LEN + seq_len + payload.len());
+    record.extend_from_slice(&[0; 4]);
+    record.extend_from_slice(&((seq_len + payload.len()) as u32).to_le_bytes());
+    match seq {
+        Some(seq) => {
+            record.push(record_type | SEQUENCED);
+            record.extend_from_slice(&seq.to_le_bytes());
+        }
+        None => record.push(record_type),
+    }
+    record.extend_from_slice(payload);
+    let crc = crc32fast::hash(&record[4..]);
+    record[..4].copy_from_slice(&crc.to_le_bytes());
+    record
+}
+
+/// Decodes a complete set or remove record at position `pos` of the log file
+/// of generation `gen`, encrypted with `cipher` if any, header included.
+///
+/// Returns `None` if the record is truncated, fails the checksum or has an
+/// invalid payload.
+pub fn decode(record: &[u8], gen: u64, pos: u64, cipher: Option<&FileCipher>) -> Option<Command> {
+    let (record_type, seq, payload) = split_seq(record[8], check(record)?)?;
+    let payload = open(payload, gen, pos, record_type, seq, cipher)?;
+    decode_payload(record_type, &payload)
+}
+
+/// Decodes a complete record of any type at position `pos` of the log file of
+/// generation `gen`, encrypted with `cipher` if any, header included.
+///
+/// Returns the sequence number of the record and every command of the record
+/// with the range of its own record, relative to the start of `record`.
+fn decode_all(
+    record: &[u8],
+    gen: u64,
+    pos: u64,
+    cipher: Option<&FileCipher>,
+) -> Option<(u64, Commands)> {
+    let (record_type, seq, payload) = split_seq(record[8], check(record)?)?;
+    if record_type != RecordType::Batch as u8 {
+        let payload = open(payload, gen, pos, record_type, seq, cipher)?;
+        let cmd = decode_payload(record_type, &payload)?;
+        return Some((seq?, vec![(cmd, 0..record.len() as u64)]));
+    }
+    let mut cmds = Vec::new();
+    let mut start = record.len() - payload.len();
+    if let Some(cipher) = cipher {
+        let tag = payload.get(..TAG_LEN)?;
+        cipher.open(pos, &aad(record_type, seq, gen), tag)?;
+        start += TAG_LEN;
+    }
+    while start < record.len() {
+        let inner = &record[start..];
+        if inner.len() < HEADER_LEN {
+            return None;
+        }
+        let len =
+            HEADER_LEN + u32::from_le_bytes([inner[4], inner[5], inner[6], inner[7]]) as usize;
+        let cmd = decode(inner.get(..len)?, gen, pos + start as u64, cipher)?;
+        cmds.push((cmd, start as u64..(start + len) as u64));
+        start += len;
+    }
+    // only the records inside a batch go without a sequence number
+    Some((seq?, cmds))
+}
+
+/// Splits the sequence number off the payload of a sequenced record.
+///
+/// Returns the record type without the flag, the sequence number, which is
+/// `None` for a record without one, and the rest of the payload.
+fn split_seq(record_type: u8, payload: &[u8]) -> Option<(u8, Option<u64>, &[u8])> {
+    if record_type & SEQUENCED == 0 {
+        return Some((record_type, None, payload));
+    }
+    let seq = u64::from_le_bytes(payload.get(..8)?.try_into().unwrap());
+    Some((record_type & !SEQUENCED, Some(seq), &payload[8..]))
+}
+
+/// Opens the payload of the record of type `record_type` with sequence number
+/// `seq` at `pos` of the log file of generation `gen` if the file is
+/// encrypted.
+fn open<'a>(
+    payload: &'a [u8],
+    gen: u64,
+    pos: u64,
+    record_type: u8,
+    seq: Option<u64>,
+    cipher: Option<&FileCipher>,
+) -> Option<Cow<'a, [u8]>> {
+    match cipher {
+        Some(cipher) => {
+            let aad = aad(record_type, seq, gen);
+            cipher.open(pos, &aad, payload).map(Cow::Owned)
+        }
+        None => Some(Cow::Borrowed(payload)),
+    }
+}
+
+/// Returns the payload of `record` if its length and checksum are right.
+fn check(record: &[u8]) -> Option<&[u8]> {
+    if record.len() < HEADER_LEN {
+        return None;
+    }
+    let crc = u32::from_le_bytes([record[0], record[1], record[2], record[3]]);
+    let len = u32::from_le_bytes([record[4], record[5], record[6], record[7]]) as usize;
+    if record.len() != HEADER_LEN + len || crc32fast::hash(&record[4..]) != crc {
+        return None;
+    }
+    Some(&record[HEADER_LEN..])
+}
+
+/// Reads the next record from `reader`, which is positioned at offset `pos`
+/// of the log file of generation `gen`, encrypted with `cipher` if any.
+///
+/// Returns the sequence number of the record, its commands, one for a set or a
+/// remove and all of them for a batch, each with the position of its own
+/// record in the file, and the length of the whole record. Returns `None` at
+/// the end of the file.
+/// A record cut off by the end of the file is reported as
+/// `KvsError::IncompleteRecord`, any other damage as `KvsError::Corruption`.
+pub fn read_record<R: Read>(
+    reader: &mut R,
+    gen: u64,
+    pos: u64,
+    cipher: Option<&FileCipher>,
+) -> Result<Option<Record>> {
+    let mut record = vec![0; HEADER_LEN];
+    match read_full(reader, &mut record)? {
+        0 => return Ok(None),
+        n if n < HEADER_LEN => return Err(KvsError::IncompleteRecord { gen, pos }),
+        _ => {}
+    }
+    let len = u32::from_le_bytes([record[4], record[5], record[6], record[7]]) as usize;
+    record.resize(HEADER_LEN + len, 0);
+    if read_full(reader, &mut record[HEADER_LEN..])? < len {
+        return Err(KvsError::IncompleteRecord { gen, pos });
+    }
+    let (seq, cmds) =
+        decode_all(&record, gen, pos, cipher).ok_or(KvsError::Corruption { gen, pos })?;
+    let cmds = cmds
+        .into_iter()
+        .map(|(cmd, range)| (cmd, pos + range.start..pos + range.end))
+        .collect();
+    Ok(Some(Record {
+        seq,
+        cmds,
+        len: record.len() as u64,
+    }))
+}
+
+fn decode_payload(record_type: u8, payload: &[u8]) -> Option<Command> {
+    let codec = Compression::from_u8((record_type & CODEC_MASK) >> CODEC_SHIFT)?;
+    let (keyspace, payload) = if record_type & KEYSPACE == 0 {
+        (DEFAULT_KEYSPACE, payload)
+    } else {
+        let keyspace = u32::from_le_bytes(payload.get(..4)?.try_into().unwrap());
+        (keyspace, &payload[4..])
+    };
+    match RecordType::from_u8(record_type & !CODEC_MASK & !KEYSPACE)? {
+        RecordType::Set => decode_set(keyspace, payload, codec, None),
+        RecordType::ExpiringSet => {
+            let expires_at = u64::from_le_bytes(payload.get(..8)?.try_into().unwrap());
+            decode_set(keyspace, &payload[8..], codec, Some(expires_at))
+        }
+        RecordType::Remove if codec == Compression::None => {
+            Some(Command::remove(keyspace, payload.to_vec()))
+        }
+        // a batch is never nested, nor read on its own
+        RecordType::Remove | RecordType::Batch => None,
+    }
+}
+
+fn decode_set(
+    keyspace: u32,
+    payload: &[u8],
+    codec: Compression,
+    expires_at: Option<u64>,
+) -> Option<Command> {
+    if payload.len() < 4 {
+        return None;
+    }
+    let key_len = u32::from_le_bytes([payload[0], payload[1], payload[2], payload[3]]) as usize;
+    if payload.len() < 4 + key_len {
+        return None;
+    }
+    let (key, value) = payload[4..].split_at(key_len);
+    Some(Command::Set {
+        keyspace,
+        key: key.to_vec(),
+        value: compression::decompress(codec, value)?,
+        expires_at,
+    })
+}
+
+/// Reads until `buf` is full or the end of the stream is reached.
+///
+/// Returns the number of bytes read.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
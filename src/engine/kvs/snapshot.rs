@@ -0,0 +1,107 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::ops::RangeBounds;
+use std::sync::{Arc, Mutex};
+
+use super::{get_at, scan_at, Index, KvStoreReader, KvStoreWriter};
+use crate::engine::{scan, ByteScan, Scan, ScanOptions};
+use crate::Result;
+
+/// Read-only view of a `KvStore` as of the write acknowledged last when it was
+/// taken, returned by `KvStore::snapshot`.
+///
+/// Writes made after the snapshot was taken are not seen, but a key that
+/// expires while the snapshot is alive is gone from it too.
+///
+/// ```rust
+/// # use kvs::{KvStore, KvsEngine, Result};
+/// # fn try_main() -> Result<()> {
+/// # let dir = tempfile::TempDir::new()?;
+/// let store = KvStore::open(dir.path())?;
+/// store.set("key".to_owned(), "v1".to_owned())?;
+/// let snapshot = store.snapshot()?;
+/// store.set("key".to_owned(), "v2".to_owned())?;
+/// assert_eq!(snapshot.get("key".to_owned())?, Some("v1".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+pub struct Snapshot {
+    pub(super) seq: u64,
+    pub(super) index: Arc<Index>,
+    pub(super) reader: KvStoreReader,
+    // log files of the generations alive when the snapshot was taken, which
+    // are read even if a compaction deletes them
+    pub(super) files: BTreeMap<u64, Arc<File>>,
+    // unregisters the snapshot when it is dropped
+    pub(super) writer: Arc<Mutex<KvStoreWriter>>,
+}
+
+impl Snapshot {
+    /// Returns the sequence number of the last write the snapshot sees.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// Gets the string value of a given string key as of the snapshot.
+    ///
+    /// Returns `None` if the given key did not exist.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.into_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Gets the value of a given key as of the snapshot.
+    ///
+    /// Returns `None` if the given key did not exist.
+    pub fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let value = get_at(&self.index, &self.reader, &self.files, &key, self.seq)?;
+        Ok(value.map(|(_, value)| value))
+    }
+
+    /// Returns the key/value pairs whose keys fell in `range` as of the
+    /// snapshot, in the order of their bytes.
+    pub fn scan_bytes<R>(&self, range: R, options: ScanOptions) -> Result<ByteScan>
+    where
+        R: RangeBounds<Vec<u8>>,
+    {
+        scan_at(
+            &self.index,
+            &self.reader,
+            &self.files,
+            range,
+            options,
+            self.seq,
+        )
+    }
+
+    /// Returns the key/value pairs whose keys started with `prefix` as of the
+    /// snapshot, in the order of their bytes.
+    pub fn scan_prefix_bytes(&self, prefix: Vec<u8>, options: ScanOptions) -> Result<ByteScan> {
+        self.scan_bytes(scan::byte_prefix_range(prefix), options)
+    }
+
+    /// Returns the string key/value pairs whose keys fell in `range` as of the
+    /// snapshot, in key order.
+    pub fn scan<R>(&self, range: R, options: ScanOptions) -> Result<Scan>
+    where
+        R: RangeBounds<String>,
+    {
+        Ok(Scan::new(
+            self.scan_bytes(scan::byte_range(range), options)?,
+        ))
+    }
+
+    /// Returns the key/value pairs whose keys started with `prefix` as of the
+    /// snapshot, in key order.
+    pub fn scan_prefix(&self, prefix: String, options: ScanOptions) -> Result<Scan> {
+        self.scan(scan::prefix_range(prefix), options)
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.writer.lock().unwrap().release_snapshot(self.seq);
+    }
+}
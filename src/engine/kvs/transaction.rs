@@ -0,0 +1,86 @@
+use std::collections::BTreeMap;
+
+use super::{expiry, get_at, Command, KvStore, Update, LATEST};
+use crate::engine::transaction::{self, Transaction, TransactionState};
+use crate::Result;
+
+/// Optimistic transaction of a `KvStore`, returned by `KvsEngine::transaction`.
+///
+/// Every read records the sequence number of the write that set the key, or
+/// `None` if the key did not exist. The commit checks under the writer lock
+/// that the keys still have these versions, and appends the writes as a
+/// single batch record.
+pub struct KvStoreTransaction {
+    store: KvStore,
+    state: TransactionState<Option<u64>>,
+}
+
+impl KvStoreTransaction {
+    pub(super) fn new(store: KvStore) -> Self {
+        KvStoreTransaction {
+            store,
+            state: TransactionState::new(),
+        }
+    }
+}
+
+impl Transaction for KvStoreTransaction {
+    fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let store = &self.store;
+        self.state.get(key, |key| {
+            let value = get_at(&store.index, &store.reader, &BTreeMap::new(), key, LATEST)?;
+            Ok(match value {
+                Some((seq, value)) => (Some(value), Some(seq)),
+                None => (None, None),
+            })
+        })
+    }
+
+    fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.state.set(key, value);
+    }
+
+    fn remove_bytes(&mut self, key: Vec<u8>) {
+        self.state.remove(key);
+    }
+
+    /// Applies the writes of the transaction atomically if none of the keys
+    /// it read was written since.
+    ///
+    /// Like a compare-and-swap, the commit is not batched with concurrent
+    /// writes.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::TransactionConflict` if a key read by the
+    /// transaction was written since, and propagates I/O errors during writing
+    /// the log.
+    fn commit(self) -> Result<()> {
+        let store = self.store;
+        let mut writer = store.writer.lock().unwrap();
+        let now = expiry::now_millis();
+        for (key, (_, seq)) in &self.state.reads {
+            let current = store
+                .index
+                .get(key)
+                .and_then(|entry| entry.value().version_at(LATEST, now))
+                .map(|(seq, _)| seq);
+            if current != *seq {
+                return Err(transaction::conflict(key));
+            }
+        }
+        if self.state.writes.is_empty() {
+            return Ok(());
+        }
+        let cmds = self
+            .state
+            .writes
+            .into_iter()
+            .map(|(key, value)| match value {
+                Some(value) => Command::set(store.keyspace, key, value),
+                None => Command::remove(store.keyspace, key),
+            })
+            .collect();
+        writer.write_group(vec![Update::Batch(cmds)]).remove(0)
+    }
+}
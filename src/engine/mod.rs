@@ -1,14 +1,160 @@
-use crate::error::Result;
+use std::ops::RangeBounds;
+use std::time::Duration;
+
+use crate::error::{KvsError, Result};
 
 /// Trait for key-value store engine.
 pub trait KvsEngine: Clone + Send + 'static {
-    fn set(&self, key: String, value: String) -> Result<()>;
-    fn get(&self, key: String) -> Result<Option<String>>;
-    fn remove(&self, key: String) -> Result<()>;
+    /// Transaction of the engine, returned by `KvsEngine::transaction`.
+    type Transaction: Transaction;
+
+    /// Sets the value of `key` to `value`, both arbitrary bytes.
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+
+    /// Gets the value of `key`, or `None` if the key does not exist.
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+
+    /// Removes `key`, failing with `KvsError::KeyNotFound` if it does not
+    /// exist.
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()>;
+
+    /// Sets the value of a string key to a string.
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Gets the string value of a string key.
+    ///
+    /// Fails with `KvsError::Utf8Error` if the value is not valid UTF-8.
+    fn get(&self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.into_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Removes a string key.
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Sets the value of `key` until `ttl` has passed, after which the key no
+    /// longer exists. Setting the key again without a TTL keeps it forever.
+    fn set_with_ttl_bytes(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()>;
+
+    /// Sets the value of a string key to a string until `ttl` has passed.
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_with_ttl_bytes(key.into_bytes(), value.into_bytes(), ttl)
+    }
+
+    /// Applies all the changes of `batch` atomically.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+
+    /// Sets `key` to `new`, or removes it if `new` is `None`, if and only if
+    /// its current value is `expected`, `None` meaning that the key does not
+    /// exist.
+    ///
+    /// Returns whether the value was swapped. The comparison and the write are
+    /// atomic: no other write to the key can happen in between.
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool>;
+
+    /// Swaps the string value of a string key, like `compare_and_swap_bytes`.
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        self.compare_and_swap_bytes(
+            key.into_bytes(),
+            expected.map(String::into_bytes),
+            new.map(String::into_bytes),
+        )
+    }
+
+    /// Sets `key` to `value` if the key does not exist.
+    ///
+    /// Returns whether the value was set.
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool> {
+        self.compare_and_swap(key, None, Some(value))
+    }
+
+    /// Removes `key` if its value is `expected`.
+    ///
+    /// Returns whether the key was removed.
+    fn remove_if_equals(&self, key: String, expected: String) -> Result<bool> {
+        self.compare_and_swap(key, Some(expected), None)
+    }
+
+    /// Starts an optimistic transaction.
+    fn transaction(&self) -> Self::Transaction;
+
+    /// Returns the key/value pairs whose keys fall in `range`, in the order of
+    /// their bytes.
+    ///
+    /// The scan sees the keys present when it starts. Writes made while it
+    /// runs may or may not be seen, but compactions never change what it
+    /// returns.
+    fn scan_bytes<R>(&self, range: R, options: ScanOptions) -> Result<ByteScan>
+    where
+        R: RangeBounds<Vec<u8>>;
+
+    /// Returns the key/value pairs whose keys start with `prefix`, in the order
+    /// of their bytes.
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>, options: ScanOptions) -> Result<ByteScan> {
+        self.scan_bytes(scan::byte_prefix_range(prefix), options)
+    }
+
+    /// Returns the string key/value pairs whose keys fall in `range`, in key
+    /// order.
+    ///
+    /// A key or value in the range that is not valid UTF-8 fails its item with
+    /// `KvsError::Utf8Error`; `scan_bytes` returns them.
+    fn scan<R>(&self, range: R, options: ScanOptions) -> Result<Scan>
+    where
+        R: RangeBounds<String>,
+    {
+        Ok(Scan::new(
+            self.scan_bytes(scan::byte_range(range), options)?,
+        ))
+    }
+
+    /// Returns the key/value pairs whose keys start with `prefix`, in key order.
+    fn scan_prefix(&self, prefix: String, options: ScanOptions) -> Result<Scan> {
+        self.scan(scan::prefix_range(prefix), options)
+    }
+}
+
+/// Checks that `name` can name a keyspace: it is not empty and does not start
+/// with `__`, which is kept for the trees the engines use internally.
+pub(crate) fn check_keyspace_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.starts_with("__") {
+        return Err(KvsError::InvalidKeyspaceName(name.to_owned()));
+    }
+    Ok(())
 }
 
-pub use self::kvs::KvStore;
-pub use self::sled::SledKvsEngine;
+pub use self::batch::WriteBatch;
+pub use self::dump::{export, import, DumpFormat, ImportOptions, DEFAULT_IMPORT_BATCH_SIZE};
+pub use self::durability::Durability;
+pub use self::kvs::{
+    CompactionTrigger, Compression, EncryptionKey, KvStore, KvStoreOptions, KvStoreStats,
+    KvStoreTransaction, RecoveryMode, Snapshot,
+};
+pub use self::scan::{ByteScan, Scan, ScanOptions};
+pub use self::sled::{SledKvsEngine, SledTransaction};
+pub use self::transaction::Transaction;
 
+mod batch;
+mod dump;
+mod durability;
+mod expiry;
 mod kvs;
+mod scan;
 mod sled;
+mod transaction;
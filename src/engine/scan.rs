@@ -0,0 +1,150 @@
+use std::ops::{Bound, RangeBounds};
+
+use crate::Result;
+
+/// Options of `KvsEngine::scan` and `KvsEngine::scan_prefix`.
+///
+/// ```rust
+/// # use kvs::ScanOptions;
+/// // the last 10 keys of the range, in reverse order
+/// let options = ScanOptions::new().limit(10).reverse(true);
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ScanOptions {
+    pub(crate) limit: Option<usize>,
+    pub(crate) reverse: bool,
+}
+
+impl ScanOptions {
+    /// Creates options scanning every key of the range in key order.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns at most `limit` key/value pairs.
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Returns the key/value pairs in reverse key order, starting from the end
+    /// of the range. A limit then keeps the last keys of the range.
+    pub fn reverse(mut self, reverse: bool) -> Self {
+        self.reverse = reverse;
+        self
+    }
+}
+
+/// Iterator over the string key/value pairs returned by a scan.
+///
+/// A key or value that is not valid UTF-8 fails its item with
+/// `KvsError::Utf8Error`, and the scan goes on with the next pair.
+pub struct Scan {
+    inner: ByteScan,
+}
+
+impl Scan {
+    pub(crate) fn new(inner: ByteScan) -> Scan {
+        Scan { inner }
+    }
+}
+
+impl Iterator for Scan {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let pair = self
+            .inner
+            .next()?
+            .and_then(|(key, value)| Ok((String::from_utf8(key)?, String::from_utf8(value)?)));
+        Some(pair)
+    }
+}
+
+/// Key/value pair returned by a `ByteScan`.
+type BytePair = (Vec<u8>, Vec<u8>);
+
+/// Iterator over the key/value pairs returned by a scan of binary keys.
+pub struct ByteScan {
+    inner: Box<dyn Iterator<Item = Result<BytePair>> + Send>,
+}
+
+impl ByteScan {
+    pub(crate) fn new<I>(inner: I) -> ByteScan
+    where
+        I: Iterator<Item = Result<BytePair>> + Send + 'static,
+    {
+        ByteScan {
+            inner: Box::new(inner),
+        }
+    }
+}
+
+impl Iterator for ByteScan {
+    type Item = Result<BytePair>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next()
+    }
+}
+
+/// Returns the range of the keys starting with `prefix`.
+///
+/// Keys are ordered by their UTF-8 bytes, which is the order of their chars,
+/// so the range ends before the smallest string greater than every key with
+/// the prefix: the prefix with its last char incremented, after dropping the
+/// trailing chars that cannot be.
+pub(crate) fn prefix_range(prefix: String) -> (Bound<String>, Bound<String>) {
+    let mut end = prefix.clone();
+    let end = loop {
+        match end.pop() {
+            Some(c) => {
+                if let Some(next) = next_char(c) {
+                    end.push(next);
+                    break Bound::Excluded(end);
+                }
+            }
+            None => break Bound::Unbounded,
+        }
+    };
+    (Bound::Included(prefix), end)
+}
+
+/// Returns the range of the binary keys starting with `prefix`: the prefix with
+/// its last byte incremented, after dropping the trailing `0xff` bytes.
+pub(crate) fn byte_prefix_range(prefix: Vec<u8>) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    let mut end = prefix.clone();
+    let end = loop {
+        match end.pop() {
+            Some(0xff) => {}
+            Some(b) => {
+                end.push(b + 1);
+                break Bound::Excluded(end);
+            }
+            None => break Bound::Unbounded,
+        }
+    };
+    (Bound::Included(prefix), end)
+}
+
+/// Returns the bounds of `range` as the bytes of the keys, which are ordered
+/// the same way.
+pub(crate) fn byte_range<R>(range: R) -> (Bound<Vec<u8>>, Bound<Vec<u8>>)
+where
+    R: RangeBounds<String>,
+{
+    let bound = |bound: Bound<&String>| match bound {
+        Bound::Included(key) => Bound::Included(key.as_bytes().to_vec()),
+        Bound::Excluded(key) => Bound::Excluded(key.as_bytes().to_vec()),
+        Bound::Unbounded => Bound::Unbounded,
+    };
+    (bound(range.start_bound()), bound(range.end_bound()))
+}
+
+fn next_char(c: char) -> Option<char> {
+    match c {
+        // skip the surrogates, which are not chars
+        '\u{d7ff}' => Some('\u{e000}'),
+        _ => char::from_u32(c as u32 + 1),
+    }
+}
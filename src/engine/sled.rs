@@ -1,43 +1,520 @@
-use super::KvsEngine;
-use crate::{KvsError, Result};
-use sled::{Db, Tree};
+use std::fs;
+use std::io;
+use std::ops::RangeBounds;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use super::batch::BatchOp;
+use super::durability::PeriodicTask;
+use super::expiry;
+use super::transaction::{self, Transaction, TransactionState};
+use super::{check_keyspace_name, ByteScan, KvsEngine, ScanOptions, WriteBatch};
+use crate::{Durability, KvsError, Result};
+use sled::transaction::{
+    self as sled_transaction, ConflictableTransactionResult, TransactionError, Transactional,
+    TransactionalTree,
+};
+use sled::{Batch, Db, IVec, Tree};
+
+/// Name of the tree holding the expiry time of every key of the default
+/// keyspace set with a TTL. The tree of the keyspace `name` is
+/// `__kvs_ttl/name`.
+const TTL_TREE: &str = "__kvs_ttl";
+
+/// How often expired keys are removed.
+const REAP_INTERVAL: Duration = Duration::from_secs(1);
 
 /// Wrapper of `sled::Db`
+///
+/// Sled keeps writes in memory and writes them back from its own background
+/// thread, so unlike `KvStore` a write that has not been flushed can be lost
+/// even if only the process crashes. The `Durability` modes guarantee:
+///
+/// - `Durability::Always`: every acknowledged write survives a process crash
+///   and a power failure.
+/// - `Durability::Interval(d)`: every write acknowledged more than `d` before
+///   the crash or power failure survives.
+/// - `Durability::Never`: only what sled flushed on its own survives, see
+///   `sled::Config::flush_every_ms`.
+///
+/// The expiry times of keys set with a TTL are kept in a separate tree, and a
+/// background thread removes the expired keys every second.
+///
+/// Every named keyspace is a tree of the database, see
+/// `SledKvsEngine::create_keyspace`.
 #[derive(Clone)]
-pub struct SledKvsEngine(Db);
+pub struct SledKvsEngine {
+    db: Db,
+    // tree of the keyspace of the handle, the default tree of `db` unless
+    // the handle was returned for a named keyspace
+    data: Tree,
+    // expiry time of every key of `data` set with a TTL, as big-endian
+    // milliseconds since the Unix epoch
+    ttl: Tree,
+    // held while keyspaces are created, dropped or reaped, so that the reaper
+    // never opens the trees of a keyspace again while it is being dropped
+    keyspaces: Arc<Mutex<()>>,
+    // read-locked by every write and write-locked by `checkpoint`, so that
+    // the copy is taken at a single point in time
+    writes: Arc<RwLock<()>>,
+    durability: Durability,
+    // removes the expired keys
+    _reaper: Arc<PeriodicTask>,
+    // flushes the database in `Durability::Interval` mode
+    _syncer: Option<Arc<PeriodicTask>>,
+}
 
 impl SledKvsEngine {
-    /// Creates a `SledKvsEngine` from `sled::Db`.
-    pub fn new(db: Db) -> Self {
-        SledKvsEngine(db)
+    /// Creates a `SledKvsEngine` from `sled::Db` which flushes every write.
+    ///
+    /// Returns an error if the expiry tree cannot be opened or the expiry
+    /// thread cannot be spawned.
+    pub fn new(db: Db) -> Result<Self> {
+        Self::with_durability(db, Durability::Always)
+    }
+
+    /// Creates a `SledKvsEngine` from `sled::Db` with the given durability.
+    pub fn with_durability(db: Db, durability: Durability) -> Result<Self> {
+        let ttl = db.open_tree(TTL_TREE)?;
+        let keyspaces = Arc::new(Mutex::new(()));
+        let reaper = {
+            let db = db.clone();
+            let keyspaces = Arc::clone(&keyspaces);
+            PeriodicTask::spawn("sled-reaper", REAP_INTERVAL, move || {
+                let _keyspaces = keyspaces.lock().unwrap();
+                reap_expired(&db, &db.open_tree(TTL_TREE)?)?;
+                for name in keyspace_names(&db) {
+                    reap_expired(&db.open_tree(&name)?, &db.open_tree(ttl_tree(&name))?)?;
+                }
+                Ok(())
+            })?
+        };
+        let syncer = match durability {
+            Durability::Interval(interval) => {
+                let db = db.clone();
+                let syncer = PeriodicTask::spawn("sled-sync", interval, move || {
+                    db.flush()?;
+                    Ok(())
+                })?;
+                Some(Arc::new(syncer))
+            }
+            Durability::Always | Durability::Never => None,
+        };
+        Ok(SledKvsEngine {
+            data: (*db).clone(),
+            db,
+            ttl,
+            keyspaces,
+            writes: Arc::new(RwLock::new(())),
+            durability,
+            _reaper: Arc::new(reaper),
+            _syncer: syncer,
+        })
+    }
+
+    /// Writes a copy of the database to `dest` that `sled::open` can open,
+    /// while the engine keeps serving reads.
+    ///
+    /// Every tree, including the expiry times and the keyspaces, is copied
+    /// pair by pair and the copy is flushed. Writes wait until the copy is
+    /// done, so that it holds every write acknowledged before the call, none
+    /// made after it, and either all or none of the writes of a batch or a
+    /// transaction. A failed copy is removed.
+    ///
+    /// # Errors
+    ///
+    /// It returns an I/O error if `dest` already holds a database, and
+    /// propagates sled errors during opening, writing and flushing the copy.
+    pub fn checkpoint(&self, dest: impl AsRef<Path>) -> Result<()> {
+        let dest = dest.as_ref();
+        let copy = sled::open(dest)?;
+        if copy.was_recovered() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{:?} already holds a database", dest),
+            )
+            .into());
+        }
+        let copied = self.copy_to(&copy);
+        drop(copy);
+        if copied.is_err() {
+            // a partial copy would make the next attempt fail
+            let _ = fs::remove_dir_all(dest);
+        }
+        copied
+    }
+
+    /// Copies every tree of the database to `copy` and flushes it.
+    fn copy_to(&self, copy: &Db) -> Result<()> {
+        // no keyspace is dropped nor key reaped halfway through the copy
+        let _keyspaces = self.keyspaces.lock().unwrap();
+        let _writes = self.writes.write().unwrap();
+        for name in self.db.tree_names() {
+            let tree = self.db.open_tree(&name)?;
+            let tree_copy = copy.open_tree(&name)?;
+            for entry in tree.iter() {
+                let (key, value) = entry?;
+                tree_copy.insert(key, value)?;
+            }
+        }
+        copy.flush()?;
+        Ok(())
+    }
+
+    /// Returns a handle to the keyspace `name` of the database, which shares
+    /// the background threads of this one.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyspaceNotFound` if there is no such keyspace.
+    pub fn keyspace(&self, name: &str) -> Result<SledKvsEngine> {
+        let _keyspaces = self.keyspaces.lock().unwrap();
+        if check_keyspace_name(name).is_err() || !self.db.tree_names().contains(&name.into()) {
+            return Err(KvsError::KeyspaceNotFound(name.to_owned()));
+        }
+        self.open_keyspace(name)
+    }
+
+    /// Creates the empty keyspace `name`, a tree of the database with the same
+    /// name, and returns a handle to it.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::InvalidKeyspaceName` if `name` is empty or starts
+    /// with `__`, `KvsError::KeyspaceExists` if the keyspace already exists,
+    /// and propagates sled errors during creating the tree.
+    pub fn create_keyspace(&self, name: &str) -> Result<SledKvsEngine> {
+        check_keyspace_name(name)?;
+        let _keyspaces = self.keyspaces.lock().unwrap();
+        if self.db.tree_names().contains(&name.into()) {
+            return Err(KvsError::KeyspaceExists(name.to_owned()));
+        }
+        let engine = self.open_keyspace(name)?;
+        self.flush()?;
+        Ok(engine)
+    }
+
+    /// Drops the keyspace `name` and all its keys, with `sled::Db::drop_tree`.
+    ///
+    /// Unlike with `KvStore::drop_keyspace`, the handles to a dropped keyspace
+    /// must not be used any more.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyspaceNotFound` if there is no such keyspace,
+    /// and propagates sled errors during dropping the trees.
+    pub fn drop_keyspace(&self, name: &str) -> Result<()> {
+        let _keyspaces = self.keyspaces.lock().unwrap();
+        if check_keyspace_name(name).is_err() || !self.db.drop_tree(name)? {
+            return Err(KvsError::KeyspaceNotFound(name.to_owned()));
+        }
+        self.db.drop_tree(ttl_tree(name))?;
+        self.flush()
+    }
+
+    /// Returns the names of the keyspaces of the database in order, without
+    /// the default keyspace, which `SledKvsEngine::new` returns.
+    pub fn list_keyspaces(&self) -> Vec<String> {
+        keyspace_names(&self.db)
+    }
+
+    /// Returns a handle to the keyspace `name`, creating its trees if they do
+    /// not exist.
+    fn open_keyspace(&self, name: &str) -> Result<SledKvsEngine> {
+        Ok(SledKvsEngine {
+            data: self.db.open_tree(name)?,
+            ttl: self.db.open_tree(ttl_tree(name))?,
+            ..self.clone()
+        })
+    }
+
+    /// Runs `f` on the data and expiry trees in a single sled transaction.
+    fn run_transaction<F, T>(&self, f: F) -> Result<T>
+    where
+        F: Fn(&TransactionalTree, &TransactionalTree) -> ConflictableTransactionResult<T, KvsError>,
+    {
+        let _writes = self.writes.read().unwrap();
+        run_transaction(&self.data, &self.ttl, f)
+    }
+
+    /// Flushes the database in `Durability::Always` mode.
+    fn flush(&self) -> Result<()> {
+        if self.durability == Durability::Always {
+            self.db.flush()?;
+        }
+        Ok(())
     }
 }
 
 impl KvsEngine for SledKvsEngine {
-    fn set(&self, key: String, value: String) -> Result<()> {
-        let tree: &Tree = &self.0;
-        tree.insert(key, value.into_bytes()).map(|_| ())?;
-        tree.flush()?;
-        Ok(())
+    type Transaction = SledTransaction;
+
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.run_transaction(|data, ttl| {
+            data.insert(key.as_slice(), value.as_slice())?;
+            ttl.remove(key.as_slice())?;
+            Ok(())
+        })?;
+        self.flush()
     }
 
-    fn get(&self, key: String) -> Result<Option<String>> {
-        let tree: &Tree = &self.0;
-        Ok(tree
-            .get(key)?
-            .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec())
-            .map(String::from_utf8)
-            .transpose()?)
+    fn set_with_ttl_bytes(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let expires_at = expiry::expiry_time(ttl);
+        self.run_transaction(|data, ttl| {
+            data.insert(key.as_slice(), value.as_slice())?;
+            ttl.insert(key.as_slice(), &expires_at.to_be_bytes())?;
+            Ok(())
+        })?;
+        self.flush()
+    }
+
+    /// Gets the value of `key`.
+    ///
+    /// The value and its expiry time are not read atomically, so a get racing
+    /// with an overwrite of an expired key may return the expired value.
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let value = match self.data.get(&key)? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        if is_expired(self.ttl.get(&key)?, expiry::now_millis()) {
+            return Ok(None);
+        }
+        Ok(Some(value.to_vec()))
     }
 
-    fn remove(&self, key: String) -> Result<()> {
-        let tree: &Tree = &self.0;
-        let old_val = tree.remove(key.clone())?;
-        if old_val.is_some() {
-            tree.flush()?;
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        let now = expiry::now_millis();
+        let removed = self.run_transaction(|data, ttl| {
+            let old_val = data.remove(key.as_slice())?;
+            let expired = is_expired(ttl.remove(key.as_slice())?, now);
+            Ok(old_val.is_some() && !expired)
+        })?;
+        if removed {
+            self.flush()
+        } else {
+            Err(KvsError::KeyNotFound(
+                String::from_utf8_lossy(&key).into_owned(),
+            ))
+        }
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let mut sled_batch = Batch::default();
+        // the keys written by the batch lose their TTL
+        let mut ttl_batch = Batch::default();
+        for op in batch.ops {
+            match op {
+                BatchOp::Set { key, value } => {
+                    ttl_batch.remove(key.as_slice());
+                    sled_batch.insert(key, value)
+                }
+                BatchOp::Remove { key } => {
+                    ttl_batch.remove(key.as_slice());
+                    sled_batch.remove(key)
+                }
+            }
+        }
+        self.run_transaction(|data, ttl| {
+            data.apply_batch(&sled_batch)?;
+            ttl.apply_batch(&ttl_batch)?;
             Ok(())
+        })?;
+        self.flush()
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        let now = expiry::now_millis();
+        let swapped = self.run_transaction(|data, ttl| {
+            let current = match data.get(key.as_slice())? {
+                Some(_) if is_expired(ttl.get(key.as_slice())?, now) => None,
+                current => current,
+            };
+            if current.as_deref() != expected.as_deref() {
+                return Ok(false);
+            }
+            match &new {
+                Some(value) => data.insert(key.as_slice(), value.as_slice())?,
+                None => data.remove(key.as_slice())?,
+            };
+            ttl.remove(key.as_slice())?;
+            Ok(true)
+        })?;
+        if swapped {
+            self.flush()?;
+        }
+        Ok(swapped)
+    }
+
+    /// Starts an optimistic transaction, whose commit checks the values of the
+    /// keys it read in a sled transaction.
+    fn transaction(&self) -> SledTransaction {
+        SledTransaction {
+            engine: self.clone(),
+            state: TransactionState::new(),
+        }
+    }
+
+    /// Returns the key/value pairs whose keys fall in `range`, in key order.
+    ///
+    /// Sled iterators read a consistent view of every single key, but not of
+    /// the whole range. Keys that have expired when the scan starts are
+    /// skipped.
+    fn scan_bytes<R>(&self, range: R, options: ScanOptions) -> Result<ByteScan>
+    where
+        R: RangeBounds<Vec<u8>>,
+    {
+        let now = expiry::now_millis();
+        let ttl = self.ttl.clone();
+        let iter = self
+            .data
+            .range::<Vec<u8>, _>((range.start_bound().cloned(), range.end_bound().cloned()));
+        let iter: Box<dyn Iterator<Item = sled::Result<_>> + Send> = if options.reverse {
+            Box::new(iter.rev())
         } else {
-            Err(KvsError::KeyNotFound(key))
+            Box::new(iter)
+        };
+        let live = iter.filter_map(move |entry| {
+            let live = entry.and_then(|(key, value)| {
+                let expires_at = ttl.get(&key)?;
+                Ok(Some((key, value)).filter(|_| !is_expired(expires_at, now)))
+            });
+            live.transpose()
+        });
+        Ok(ByteScan::new(
+            live.take(options.limit.unwrap_or(usize::MAX)).map(|entry| {
+                let (key, value) = entry?;
+                Ok((key.to_vec(), value.to_vec()))
+            }),
+        ))
+    }
+}
+
+/// Optimistic transaction of a `SledKvsEngine`, returned by
+/// `KvsEngine::transaction`.
+///
+/// Sled has no version numbers, so the commit checks in a sled transaction
+/// that the keys read still have the values they were read with. A key written
+/// and then set back to the value read does not conflict, which is still
+/// equivalent to running the transaction at the time of the commit.
+pub struct SledTransaction {
+    engine: SledKvsEngine,
+    state: TransactionState<()>,
+}
+
+impl Transaction for SledTransaction {
+    fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let engine = &self.engine;
+        self.state
+            .get(key, |key| Ok((engine.get_bytes(key.to_vec())?, ())))
+    }
+
+    fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.state.set(key, value);
+    }
+
+    fn remove_bytes(&mut self, key: Vec<u8>) {
+        self.state.remove(key);
+    }
+
+    /// Applies the writes of the transaction atomically if none of the keys
+    /// it read was written since.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::TransactionConflict` if a key read by the
+    /// transaction has changed.
+    fn commit(self) -> Result<()> {
+        let state = &self.state;
+        let now = expiry::now_millis();
+        self.engine.run_transaction(|data, ttl| {
+            for (key, (value, ())) in &state.reads {
+                let current = match data.get(key.as_slice())? {
+                    Some(_) if is_expired(ttl.get(key.as_slice())?, now) => None,
+                    current => current,
+                };
+                if current.as_deref() != value.as_deref() {
+                    return sled_transaction::abort(transaction::conflict(key));
+                }
+            }
+            for (key, value) in &state.writes {
+                match value {
+                    Some(value) => data.insert(key.as_slice(), value.as_slice())?,
+                    None => data.remove(key.as_slice())?,
+                };
+                ttl.remove(key.as_slice())?;
+            }
+            Ok(())
+        })?;
+        if !state.writes.is_empty() {
+            self.engine.flush()?;
         }
+        Ok(())
     }
 }
+
+/// Runs `f` on the data and expiry trees in a single sled transaction.
+fn run_transaction<F, T>(data: &Tree, ttl: &Tree, f: F) -> Result<T>
+where
+    F: Fn(&TransactionalTree, &TransactionalTree) -> ConflictableTransactionResult<T, KvsError>,
+{
+    (data, ttl)
+        .transaction(|(data, ttl)| f(data, ttl))
+        .map_err(|e| match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => e.into(),
+        })
+}
+
+/// Removes the keys that have expired.
+///
+/// A key set again since its expiry time was read keeps its new value.
+fn reap_expired(data: &Tree, ttl: &Tree) -> Result<()> {
+    let now = expiry::now_millis();
+    for entry in ttl.iter() {
+        let (key, expires_at) = entry?;
+        if !is_expired(Some(expires_at.clone()), now) {
+            continue;
+        }
+        run_transaction(data, ttl, |tx_data, tx_ttl| {
+            if tx_ttl.get(&key)?.as_ref() == Some(&expires_at) {
+                tx_data.remove(&key)?;
+                tx_ttl.remove(&key)?;
+            }
+            Ok(())
+        })?;
+    }
+    Ok(())
+}
+
+/// Returns the names of the keyspaces of `db`, which are all its trees but the
+/// ones whose names start with `__`.
+fn keyspace_names(db: &Db) -> Vec<String> {
+    let mut names: Vec<String> = db
+        .tree_names()
+        .into_iter()
+        .filter(|name| !name.starts_with(b"__"))
+        .map(|name| String::from_utf8_lossy(&name).into_owned())
+        .collect();
+    names.sort();
+    names
+}
+
+/// Returns the name of the expiry tree of the keyspace `name`.
+fn ttl_tree(name: &str) -> String {
+    format!("{}/{}", TTL_TREE, name)
+}
+
+/// Returns whether a key with the given raw expiry time has expired at `now`.
+fn is_expired(expires_at: Option<IVec>, now: u64) -> bool {
+    let expires_at = expires_at
+        .and_then(|expires_at| expires_at.as_ref().try_into().ok())
+        .map(u64::from_be_bytes);
+    expiry::is_expired(expires_at, now)
+}
@@ -0,0 +1,109 @@
+use std::collections::BTreeMap;
+
+use crate::{KvsError, Result};
+
+/// Optimistic multi-key transaction, returned by `KvsEngine::transaction`.
+///
+/// Reads go to the engine and writes are buffered until `commit`, which
+/// applies all of them atomically. The commit fails with
+/// `KvsError::TransactionConflict` and writes nothing if a key the transaction
+/// read was written by someone else in the meantime, in which case the whole
+/// transaction should be retried.
+///
+/// ```rust
+/// # use kvs::{KvsEngine, Result, Transaction};
+/// fn transfer<E: KvsEngine>(engine: &E, amount: u64) -> Result<()> {
+///     let mut tx = engine.transaction();
+///     let from: u64 = tx.get("from".to_owned())?.map_or(0, |v| v.parse().unwrap());
+///     let to: u64 = tx.get("to".to_owned())?.map_or(0, |v| v.parse().unwrap());
+///     tx.set("from".to_owned(), (from - amount).to_string());
+///     tx.set("to".to_owned(), (to + amount).to_string());
+///     tx.commit()
+/// }
+/// ```
+pub trait Transaction {
+    /// Gets the value of `key`, as written by the transaction if it did.
+    ///
+    /// Reading a key again returns the value it was read with the first time.
+    fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+
+    /// Sets the value of `key` when the transaction is committed.
+    fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>);
+
+    /// Removes `key` when the transaction is committed. Removing a key that
+    /// does not exist does nothing.
+    fn remove_bytes(&mut self, key: Vec<u8>);
+
+    /// Gets the string value of a string key.
+    ///
+    /// Fails with `KvsError::Utf8Error` if the value is not valid UTF-8.
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.into_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Sets the value of a string key to a string when the transaction is
+    /// committed.
+    fn set(&mut self, key: String, value: String) {
+        self.set_bytes(key.into_bytes(), value.into_bytes());
+    }
+
+    /// Removes a string key when the transaction is committed.
+    fn remove(&mut self, key: String) {
+        self.remove_bytes(key.into_bytes());
+    }
+
+    /// Applies the writes of the transaction atomically if none of the keys
+    /// it read was written since.
+    fn commit(self) -> Result<()>;
+}
+
+/// Reads and buffered writes of a transaction, with the version of every read
+/// key that an engine checks on commit.
+pub(crate) struct TransactionState<V> {
+    // value and version of every key read, as read the first time
+    pub(crate) reads: BTreeMap<Vec<u8>, (Option<Vec<u8>>, V)>,
+    // buffered writes, `None` removing the key
+    pub(crate) writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl<V> TransactionState<V> {
+    pub(crate) fn new() -> Self {
+        TransactionState {
+            reads: BTreeMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// Gets the value of `key` from the writes, the reads or `read`, which
+    /// returns the value and the version of the key in the engine.
+    pub(crate) fn get<F>(&mut self, key: Vec<u8>, read: F) -> Result<Option<Vec<u8>>>
+    where
+        F: FnOnce(&[u8]) -> Result<(Option<Vec<u8>>, V)>,
+    {
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(value.clone());
+        }
+        if let Some((value, _)) = self.reads.get(&key) {
+            return Ok(value.clone());
+        }
+        let (value, version) = read(&key)?;
+        self.reads.insert(key, (value.clone(), version));
+        Ok(value)
+    }
+
+    pub(crate) fn set(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.writes.insert(key, Some(value));
+    }
+
+    pub(crate) fn remove(&mut self, key: Vec<u8>) {
+        self.writes.insert(key, None);
+    }
+}
+
+/// Returns the error of a commit that conflicts on `key`.
+pub(crate) fn conflict(key: &[u8]) -> KvsError {
+    KvsError::TransactionConflict(String::from_utf8_lossy(key).into_owned())
+}
@@ -6,7 +6,6 @@ pub enum KvsError {
     IO(#[from] std::io::Error),
 
     #[error("Serde error")]
-
     Serde(#[from] serde_json::Error),
 
     #[error("Key not found")]
@@ -15,9 +14,42 @@ pub enum KvsError {
     #[error("Invalid command")]
     UnexpectedCommandType,
 
+    #[error("corrupted record in generation {gen} at offset {pos}")]
+    Corruption { gen: u64, pos: u64 },
+
+    #[error("incomplete record in generation {gen} at offset {pos}")]
+    IncompleteRecord { gen: u64, pos: u64 },
+
+    #[error("unsupported log format version {0}")]
+    UnsupportedVersion(u32),
+
+    #[error("log file of generation {gen} is encrypted with a key that was not given")]
+    WrongKey { gen: u64 },
+
     #[error("{0}")]
     StringError(String),
 
+    #[error("invalid option: {0}")]
+    InvalidOption(String),
+
+    #[error("invalid dump at line {line}: {reason}")]
+    InvalidDump { line: u64, reason: String },
+
+    #[error("keyspace {0} not found")]
+    KeyspaceNotFound(String),
+
+    #[error("keyspace {0} already exists")]
+    KeyspaceExists(String),
+
+    #[error("keyspace was dropped")]
+    KeyspaceDropped,
+
+    #[error("invalid keyspace name {0:?}")]
+    InvalidKeyspaceName(String),
+
+    #[error("transaction conflict on key {0}")]
+    TransactionConflict(String),
+
     #[error("sled error: {0}")]
     Sled(#[from] sled::Error),
 
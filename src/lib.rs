@@ -1,10 +1,16 @@
 pub use client::KvsClient;
-pub use engine::{KvStore, KvsEngine, SledKvsEngine};
+pub use engine::{
+    export, import, ByteScan, CompactionTrigger, Compression, DumpFormat, Durability,
+    EncryptionKey, ImportOptions, KvStore, KvStoreOptions, KvStoreStats, KvStoreTransaction,
+    KvsEngine, RecoveryMode, Scan, ScanOptions, SledKvsEngine, SledTransaction, Snapshot,
+    Transaction, WriteBatch, DEFAULT_IMPORT_BATCH_SIZE,
+};
 pub use error::{KvsError, Result};
 pub use net::*;
 pub use server::KvsServer;
 pub use thread_pool::*;
 
+mod base64;
 mod client;
 mod engine;
 mod error;
@@ -1,10 +1,34 @@
 use serde::{Deserialize, Serialize};
 
+use crate::base64;
+
+// the *Bytes requests carry binary keys and values as base64 strings
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request {
-    Set { key: String, value: String },
-    Get { key: String },
-    Remove { key: String },
+    Set {
+        key: String,
+        value: String,
+    },
+    Get {
+        key: String,
+    },
+    Remove {
+        key: String,
+    },
+    SetBytes {
+        #[serde(with = "base64")]
+        key: Vec<u8>,
+        #[serde(with = "base64")]
+        value: Vec<u8>,
+    },
+    GetBytes {
+        #[serde(with = "base64")]
+        key: Vec<u8>,
+    },
+    RemoveBytes {
+        #[serde(with = "base64")]
+        key: Vec<u8>,
+    },
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -13,6 +37,12 @@ pub enum GetResponse {
     Err(String),
 }
 
+#[derive(Debug, Deserialize, Serialize)]
+pub enum GetBytesResponse {
+    Ok(#[serde(with = "base64::option")] Option<Vec<u8>>),
+    Err(String),
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub enum SetResponse {
     Ok(()),
@@ -30,4 +60,5 @@ pub enum Response {
     Get(GetResponse),
     Set(SetResponse),
     Remove(RemoveResponse),
+    GetBytes(GetBytesResponse),
 }
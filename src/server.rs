@@ -2,7 +2,8 @@ use std::io::{BufReader, BufWriter};
 use std::net::{TcpListener, TcpStream, ToSocketAddrs};
 
 use crate::{
-    GetResponse, KvsEngine, RemoveResponse, Request, Response, Result, SetResponse, ThreadPool,
+    GetBytesResponse, GetResponse, KvsEngine, RemoveResponse, Request, Response, Result,
+    SetResponse, ThreadPool,
 };
 
 use log::{debug, error};
@@ -56,6 +57,9 @@ fn handle_request<E: KvsEngine>(engine: E, request: Request) -> Response {
         Request::Get { key } => Response::Get(handle_get(engine, key)),
         Request::Set { key, value } => Response::Set(handle_set(engine, key, value)),
         Request::Remove { key } => Response::Remove(handle_remove(engine, key)),
+        Request::GetBytes { key } => Response::GetBytes(handle_get_bytes(engine, key)),
+        Request::SetBytes { key, value } => Response::Set(handle_set_bytes(engine, key, value)),
+        Request::RemoveBytes { key } => Response::Remove(handle_remove_bytes(engine, key)),
     }
 }
 fn handle_get<E: KvsEngine>(engine: E, key: String) -> GetResponse {
@@ -76,3 +80,21 @@ fn handle_remove<E: KvsEngine>(engine: E, key: String) -> RemoveResponse {
         Err(e) => RemoveResponse::Err(e.to_string()),
     }
 }
+fn handle_get_bytes<E: KvsEngine>(engine: E, key: Vec<u8>) -> GetBytesResponse {
+    match engine.get_bytes(key) {
+        Ok(value) => GetBytesResponse::Ok(value),
+        Err(e) => GetBytesResponse::Err(e.to_string()),
+    }
+}
+fn handle_set_bytes<E: KvsEngine>(engine: E, key: Vec<u8>, value: Vec<u8>) -> SetResponse {
+    match engine.set_bytes(key, value) {
+        Ok(_) => SetResponse::Ok(()),
+        Err(e) => SetResponse::Err(e.to_string()),
+    }
+}
+fn handle_remove_bytes<E: KvsEngine>(engine: E, key: Vec<u8>) -> RemoveResponse {
+    match engine.remove_bytes(key) {
+        Ok(_) => RemoveResponse::Ok(()),
+        Err(e) => RemoveResponse::Err(e.to_string()),
+    }
+}
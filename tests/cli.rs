@@ -1,4 +1,6 @@
 use assert_cmd::prelude::*;
+use kvs::{GetBytesResponse, GetResponse, KvsClient, Request};
+use predicates::prelude::*;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
 use std::process::Command;
@@ -212,6 +214,187 @@ fn cli_wrong_engine() {
     }
 }
 
+#[test]
+fn server_cli_invalid_kvs_options() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--compaction-ratio", "0", "--addr", "127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&[
+            "--compaction-ratio",
+            "0.5",
+            "--compaction-threshold",
+            "1024",
+            "--addr",
+            "127.0.0.1:4006",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// `kvs-server --strict-recovery` should fail on a torn record at the end of the
+// log instead of dropping it
+#[test]
+fn server_cli_strict_recovery() {
+    let temp_dir = TempDir::new().unwrap();
+    let dump_dir = TempDir::new().unwrap();
+    let jsonl = dump_dir.path().join("dump.jsonl");
+    fs::write(&jsonl, "{\"key\":\"key1\",\"value\":\"value1\"}\n").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4011", "--engine", "kvs", "--import"])
+        .arg(&jsonl)
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    let mut log = fs::read(temp_dir.path().join("1.log")).unwrap();
+    log.extend_from_slice(&[1, 2, 3]);
+    fs::write(temp_dir.path().join("1.log"), log).unwrap();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4011", "--strict-recovery", "--export"])
+        .arg(&jsonl)
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4011", "--export"])
+        .arg(&jsonl)
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+}
+
+// `kvs-server --engine sled` should fail instead of ignoring the options of
+// the kvs engine
+#[test]
+fn server_cli_sled_kvs_options() {
+    let temp_dir = TempDir::new().unwrap();
+    let dump_dir = TempDir::new().unwrap();
+    let jsonl = dump_dir.path().join("dump.jsonl");
+    let key_file = dump_dir.path().join("key");
+    fs::write(&key_file, "00".repeat(32)).unwrap();
+
+    let sled_export = || {
+        let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+        cmd.args(&["--addr", "127.0.0.1:4012", "--engine", "sled", "--export"])
+            .arg(&jsonl)
+            .current_dir(&temp_dir)
+            .env_remove("KVS_ENCRYPTION_KEY");
+        cmd
+    };
+    sled_export()
+        .arg("--key-file")
+        .arg(&key_file)
+        .assert()
+        .failure();
+    sled_export()
+        .arg("--old-key-file")
+        .arg(&key_file)
+        .assert()
+        .failure();
+    sled_export()
+        .args(&["--cache-size", "1024"])
+        .assert()
+        .failure();
+    sled_export().arg("--strict-recovery").assert().failure();
+    sled_export()
+        .env("KVS_ENCRYPTION_KEY", "00".repeat(32))
+        .assert()
+        .failure();
+    assert!(!temp_dir.path().join("engine").exists());
+
+    sled_export().assert().success();
+}
+
+// `kvs-server --export` and `--import` should move the pairs of a store to
+// another engine without serving
+#[test]
+fn server_cli_export_import() {
+    let kvs_dir = TempDir::new().unwrap();
+    let sled_dir = TempDir::new().unwrap();
+    let dump_dir = TempDir::new().unwrap();
+    let jsonl = dump_dir.path().join("dump.jsonl");
+    let csv = dump_dir.path().join("dump.csv");
+    let pairs: String = (0..10)
+        .map(|id| format!("{{\"key\":\"key{}\",\"value\":\"value{}\"}}\n", id, id))
+        .collect();
+    fs::write(&jsonl, &pairs).unwrap();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4009", "--engine", "kvs", "--import"])
+        .arg(&jsonl)
+        .args(&["--import-batch-size", "4"])
+        .env("RUST_LOG", "info")
+        .current_dir(&kvs_dir)
+        .assert()
+        .success()
+        .stderr(contains("Imported 8 pairs").and(contains("Imported 10 pairs from")));
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&[
+            "--addr",
+            "127.0.0.1:4009",
+            "--engine",
+            "kvs",
+            "--dump-format",
+            "csv",
+            "--export",
+        ])
+        .arg(&csv)
+        .current_dir(&kvs_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&[
+            "--addr",
+            "127.0.0.1:4009",
+            "--engine",
+            "sled",
+            "--dump-format",
+            "csv",
+            "--import",
+        ])
+        .arg(&csv)
+        .current_dir(&sled_dir)
+        .assert()
+        .success();
+    fs::remove_file(&jsonl).unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4009", "--engine", "sled", "--export"])
+        .arg(&jsonl)
+        .current_dir(&sled_dir)
+        .assert()
+        .success();
+    assert_eq!(fs::read_to_string(&jsonl).unwrap(), pairs);
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&[
+            "--addr",
+            "127.0.0.1:4009",
+            "--dump-format",
+            "xml",
+            "--export",
+        ])
+        .arg(&jsonl)
+        .current_dir(&kvs_dir)
+        .assert()
+        .failure();
+}
+
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
@@ -335,3 +518,184 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+// Binary keys and values should go over the wire as base64 strings
+#[test]
+fn client_binary_values() {
+    let request = Request::SetBytes {
+        key: vec![0x00, 0xff],
+        value: b"value".to_vec(),
+    };
+    assert_eq!(
+        serde_json::to_string(&request).unwrap(),
+        r#"{"SetBytes":{"key":"AP8=","value":"dmFsdWU="}}"#
+    );
+    let response: GetBytesResponse = serde_json::from_str(r#"{"Ok":"gA=="}"#).unwrap();
+    assert!(matches!(response, GetBytesResponse::Ok(Some(value)) if value == [0x80]));
+    let response: GetBytesResponse = serde_json::from_str(r#"{"Ok":null}"#).unwrap();
+    assert!(matches!(response, GetBytesResponse::Ok(None)));
+
+    let addr = "127.0.0.1:4010";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let key = vec![0x00, 0xff, 0xfe];
+    let value = vec![0x80, 0x00, 0xc3, 0x28];
+    KvsClient::connect(addr)
+        .unwrap()
+        .set_bytes(key.clone(), value.clone())
+        .unwrap();
+    let got = KvsClient::connect(addr)
+        .unwrap()
+        .get_bytes(key.clone())
+        .unwrap();
+    KvsClient::connect(addr).unwrap().remove_bytes(key).unwrap();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+    assert_eq!(got, Some(value));
+}
+
+// The string requests of older clients keep their plain JSON strings.
+#[test]
+fn client_string_requests() {
+    let request: Request = serde_json::from_str(r#"{"Set":{"key":"key","value":"AP8="}}"#).unwrap();
+    assert!(matches!(request, Request::Set { key, value } if key == "key" && value == "AP8="));
+    let request: Request = serde_json::from_str(r#"{"Get":{"key":"key"}}"#).unwrap();
+    assert!(matches!(request, Request::Get { key } if key == "key"));
+    let request: Request = serde_json::from_str(r#"{"Remove":{"key":"key"}}"#).unwrap();
+    assert!(matches!(request, Request::Remove { key } if key == "key"));
+    let response: GetResponse = serde_json::from_str(r#"{"Ok":"value"}"#).unwrap();
+    assert!(matches!(response, GetResponse::Ok(Some(value)) if value == "value"));
+}
+
+fn get_bytes_request(key: &str) -> serde_json::Result<Request> {
+    serde_json::from_str(&format!(r#"{{"GetBytes":{{"key":{}}}}}"#, key))
+}
+
+#[test]
+fn client_base64_round_trip() {
+    // test vectors of RFC 4648
+    for (bytes, encoded) in &[
+        ("", ""),
+        ("f", "Zg=="),
+        ("fo", "Zm8="),
+        ("foo", "Zm9v"),
+        ("foob", "Zm9vYg=="),
+        ("fooba", "Zm9vYmE="),
+        ("foobar", "Zm9vYmFy"),
+    ] {
+        let request = Request::GetBytes {
+            key: bytes.as_bytes().to_vec(),
+        };
+        assert_eq!(
+            serde_json::to_string(&request).unwrap(),
+            format!(r#"{{"GetBytes":{{"key":"{}"}}}}"#, encoded)
+        );
+    }
+
+    let key: Vec<u8> = (0..=255).collect();
+    for len in 0..key.len() {
+        let request = Request::GetBytes {
+            key: key[len..].to_vec(),
+        };
+        let json = serde_json::to_string(&request).unwrap();
+        let decoded: Request = serde_json::from_str(&json).unwrap();
+        assert!(matches!(decoded, Request::GetBytes { key: decoded } if decoded == key[len..]));
+    }
+}
+
+#[test]
+fn client_base64_invalid() {
+    for key in &[
+        // not a multiple of 4 digits
+        r#""Zg""#,
+        r#""Zg=""#,
+        r#""Zm9vY""#,
+        // too much padding
+        r#""Z===""#,
+        r#""====""#,
+        // padding before the last group
+        r#""Zg==Zm9v""#,
+        // unused bits set
+        r#""Zh==""#,
+        r#""Zm9=""#,
+        // outside the alphabet
+        r#""Zm9*""#,
+        r#""Zm9v-_==""#,
+        r#""Zm 9""#,
+        // not a string
+        "null",
+        "[102]",
+    ] {
+        assert!(get_bytes_request(key).is_err(), "accepted {}", key);
+    }
+    assert!(get_bytes_request(r#""Zm9vYg==""#).is_ok());
+}
+
+// Kills the server right after a write and checks that the write is still there
+// after a restart.
+fn cli_durability_after_kill(engine: &str, durability: &str, addr: &str) {
+    let temp_dir = TempDir::new().unwrap();
+    let start_server = || {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&[
+                "--engine",
+                engine,
+                "--durability",
+                durability,
+                "--addr",
+                addr,
+            ])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap()
+    };
+
+    let mut child = start_server();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    // give an interval sync the time to run
+    thread::sleep(Duration::from_millis(500));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    let mut child = start_server();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_durability_kvs_engine() {
+    // `KvStore` hands every write to the OS, so even `never` survives a kill
+    for durability in &["always", "100ms", "never"] {
+        cli_durability_after_kill("kvs", durability, "127.0.0.1:4007");
+    }
+}
+
+#[test]
+fn cli_durability_sled_engine() {
+    // sled buffers unflushed writes in the process, so `never` is not tested
+    for durability in &["always", "100ms"] {
+        cli_durability_after_kill("sled", durability, "127.0.0.1:4008");
+    }
+}
@@ -1,6 +1,15 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    CompactionTrigger, Compression, DumpFormat, Durability, EncryptionKey, ImportOptions, KvStore,
+    KvStoreOptions, KvStoreStats, KvsEngine, KvsError, RecoveryMode, Result, ScanOptions,
+    SledKvsEngine, Transaction, WriteBatch,
+};
+use std::fs::{self, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::Path;
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -127,6 +136,1807 @@ fn compaction() -> Result<()> {
     panic!("No compaction detected");
 }
 
+// A flipped bit in a record should be reported as corruption instead of being misread.
+#[test]
+fn detect_corrupted_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let log = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .find(|path| fs::metadata(path).unwrap().len() > 0)
+        .expect("no log file written");
+    let mut file = OpenOptions::new().write(true).open(&log)?;
+    // the last byte of the file belongs to the value of "key1"
+    file.seek(SeekFrom::End(-1))?;
+    file.write_all(b"X")?;
+    drop(file);
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Corruption { .. }) => Ok(()),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("corrupted log was accepted"),
+    }
+}
+
+// A store written by the first release, whose logs hold JSON commands, should be
+// upgraded in place when it is opened
+#[test]
+fn upgrade_json_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("1.log"),
+        r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","value":"value2"}}"#,
+    )?;
+    fs::write(
+        temp_dir.path().join("2.log"),
+        r#"{"Remove":{"key":"key1"}}{"Set":{"key":"key2","value":"value3"}}{"Set":{"key":"k"#,
+    )?;
+    fs::write(temp_dir.path().join("3.log"), "")?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+    for entry in fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        assert_ne!(path.extension(), Some("legacy".as_ref()));
+        assert_ne!(fs::read(&path)?.first(), Some(&b'{'));
+    }
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+
+    // a damaged JSON log is reported as corruption
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(temp_dir.path().join("1.log"), r#"{"Set":{"key":1}}"#)?;
+    fs::write(
+        temp_dir.path().join("2.log"),
+        r#"{"Remove":{"key":"key1"}}"#,
+    )?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Corruption { gen: 1, pos: 0 }) => Ok(()),
+        res => panic!("unexpected result {:?}", res.map(|_| ())),
+    }
+}
+
+// A record cut off by a crash at the end of the newest log should be dropped on open,
+// unless the store is opened in strict mode.
+#[test]
+fn recover_torn_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let strict = KvStoreOptions::new().recovery_mode(RecoveryMode::Strict);
+    let log = temp_dir.path().join("1.log");
+    let len = fs::metadata(&log)?.len();
+    // cut the record of "key2" in half
+    OpenOptions::new()
+        .write(true)
+        .open(&log)?
+        .set_len(len - 5)?;
+
+    match KvStore::open_with(temp_dir.path(), strict.clone()) {
+        Err(KvsError::IncompleteRecord { gen: 1, .. }) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("torn log was accepted in strict mode"),
+    }
+    assert_eq!(fs::metadata(&log)?.len(), len - 5);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), strict.clone())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+fn check_write_batch<E: KvsEngine>(engine: &E) -> Result<()> {
+    engine.set("from".to_owned(), "100".to_owned())?;
+    engine.set("pending".to_owned(), "10".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch.set("from".to_owned(), "90".to_owned());
+    batch.set("to".to_owned(), "10".to_owned());
+    batch.remove("pending".to_owned());
+    // removing a missing key does not fail the batch
+    batch.remove("missing".to_owned());
+    batch.set("to".to_owned(), "110".to_owned());
+    engine.write_batch(batch)?;
+    engine.write_batch(WriteBatch::new())?;
+
+    assert_eq!(engine.get("from".to_owned())?, Some("90".to_owned()));
+    assert_eq!(engine.get("to".to_owned())?, Some("110".to_owned()));
+    assert_eq!(engine.get("pending".to_owned())?, None);
+    assert_eq!(engine.get("missing".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn write_batch_kvs_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_write_batch(&KvStore::open(temp_dir.path())?)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("from".to_owned())?, Some("90".to_owned()));
+    assert_eq!(store.get("to".to_owned())?, Some("110".to_owned()));
+    assert_eq!(store.get("pending".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn write_batch_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_write_batch(&SledKvsEngine::new(sled::open(temp_dir.path())?)?)
+}
+
+// A batch cut off by a crash should be dropped as a whole.
+#[test]
+fn recover_torn_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("key1".to_owned(), "batch1".to_owned());
+    batch.set("key2".to_owned(), "batch2".to_owned());
+    store.write_batch(batch)?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let len = fs::metadata(&log)?.len();
+    // cut the last set of the batch in half, leaving the first one complete
+    OpenOptions::new()
+        .write(true)
+        .open(&log)?
+        .set_len(len - 5)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// Compaction should copy the live commands of batches.
+#[test]
+fn compact_batches() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_trigger(CompactionTrigger::StaleBytes(1024));
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for round in 0..20 {
+        let mut batch = WriteBatch::new();
+        for i in 0..10 {
+            batch.set(format!("key{}", i), format!("value{}", round));
+        }
+        if round == 19 {
+            batch.remove("key0".to_owned());
+        }
+        store.write_batch(batch)?;
+    }
+    // wait for the compaction to delete the first log file
+    let first_log = temp_dir.path().join("1.log");
+    let start = Instant::now();
+    while first_log.exists() && start.elapsed() < Duration::from_secs(10) {
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(!first_log.exists());
+
+    for store in [store, KvStore::open_with(temp_dir.path(), options)?] {
+        assert_eq!(store.get("key0".to_owned())?, None);
+        for i in 1..10 {
+            assert_eq!(store.get(format!("key{}", i))?, Some("value19".to_owned()));
+        }
+    }
+    Ok(())
+}
+
+fn check_compare_and_swap<E: KvsEngine>(engine: E) -> Result<()> {
+    let key = || "key".to_owned();
+    assert!(!engine.compare_and_swap(key(), Some("v0".to_owned()), Some("v1".to_owned()))?);
+    assert!(engine.compare_and_swap(key(), None, None)?);
+    assert_eq!(engine.get(key())?, None);
+    assert!(engine.compare_and_swap(key(), None, Some("v1".to_owned()))?);
+    assert!(!engine.compare_and_swap(key(), None, Some("v2".to_owned()))?);
+    assert!(engine.compare_and_swap(key(), Some("v1".to_owned()), Some("v2".to_owned()))?);
+    assert_eq!(engine.get(key())?, Some("v2".to_owned()));
+
+    assert!(!engine.set_if_absent(key(), "v3".to_owned())?);
+    assert!(engine.set_if_absent("other".to_owned(), "v3".to_owned())?);
+    assert_eq!(engine.get("other".to_owned())?, Some("v3".to_owned()));
+
+    assert!(!engine.remove_if_equals(key(), "v1".to_owned())?);
+    assert!(engine.remove_if_equals(key(), "v2".to_owned())?);
+    assert_eq!(engine.get(key())?, None);
+    assert!(!engine.remove_if_equals(key(), "v2".to_owned())?);
+
+    // concurrent increments through compare-and-swap never get lost
+    engine.set("counter".to_owned(), "0".to_owned())?;
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let engine = engine.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..50 {
+                    loop {
+                        let current = engine.get("counter".to_owned())?.unwrap();
+                        let next = (current.parse::<u32>().unwrap() + 1).to_string();
+                        if engine.compare_and_swap(
+                            "counter".to_owned(),
+                            Some(current),
+                            Some(next),
+                        )? {
+                            break;
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(engine.get("counter".to_owned())?, Some("200".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn compare_and_swap_kvs_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_compare_and_swap(KvStore::open(temp_dir.path())?)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, None);
+    assert_eq!(store.get("other".to_owned())?, Some("v3".to_owned()));
+    assert_eq!(store.get("counter".to_owned())?, Some("200".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn compare_and_swap_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_compare_and_swap(SledKvsEngine::new(sled::open(temp_dir.path())?)?)
+}
+
+fn check_transactions<E: KvsEngine>(engine: E) -> Result<()> {
+    let owned = |s: &str| s.to_owned();
+    engine.set(owned("from"), owned("100"))?;
+    let mut tx = engine.transaction();
+    assert_eq!(tx.get(owned("from"))?, Some(owned("100")));
+    tx.set(owned("from"), owned("90"));
+    tx.set(owned("to"), owned("10"));
+    tx.remove(owned("missing"));
+    assert_eq!(tx.get(owned("to"))?, Some(owned("10")));
+    assert_eq!(engine.get(owned("to"))?, None);
+    tx.commit()?;
+    assert_eq!(engine.get(owned("from"))?, Some(owned("90")));
+    assert_eq!(engine.get(owned("to"))?, Some(owned("10")));
+
+    // a key read by the transaction was written since
+    let mut tx = engine.transaction();
+    tx.get(owned("from"))?;
+    engine.set(owned("from"), owned("80"))?;
+    tx.set(owned("to"), owned("20"));
+    assert!(matches!(tx.commit(), Err(KvsError::TransactionConflict(key)) if key == "from"));
+    assert_eq!(engine.get(owned("to"))?, Some(owned("10")));
+
+    // a key read as missing was set since
+    let mut tx = engine.transaction();
+    assert_eq!(tx.get(owned("new"))?, None);
+    engine.set(owned("new"), owned("1"))?;
+    tx.set(owned("other"), owned("1"));
+    assert!(matches!(tx.commit(), Err(KvsError::TransactionConflict(key)) if key == "new"));
+    assert_eq!(engine.get(owned("other"))?, None);
+
+    // keys written without being read never conflict
+    let mut tx = engine.transaction();
+    tx.set(owned("from"), owned("0"));
+    engine.set(owned("from"), owned("70"))?;
+    tx.commit()?;
+    assert_eq!(engine.get(owned("from"))?, Some(owned("0")));
+
+    // concurrent transfers retried on conflicts never lose an update
+    engine.set(owned("a"), owned("100"))?;
+    engine.set(owned("b"), owned("100"))?;
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let engine = engine.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..25 {
+                    loop {
+                        let mut tx = engine.transaction();
+                        let a: u32 = tx.get(owned("a"))?.unwrap().parse().unwrap();
+                        let b: u32 = tx.get(owned("b"))?.unwrap().parse().unwrap();
+                        tx.set(owned("a"), (a - 1).to_string());
+                        tx.set(owned("b"), (b + 1).to_string());
+                        match tx.commit() {
+                            Ok(()) => break,
+                            Err(KvsError::TransactionConflict(_)) => {}
+                            Err(e) => return Err(e),
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(engine.get(owned("a"))?, Some(owned("0")));
+    assert_eq!(engine.get(owned("b"))?, Some(owned("200")));
+    Ok(())
+}
+
+#[test]
+fn transactions_kvs_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_transactions(KvStore::open(temp_dir.path())?)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("from".to_owned())?, Some("0".to_owned()));
+    assert_eq!(store.get("b".to_owned())?, Some("200".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn transactions_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_transactions(SledKvsEngine::new(sled::open(temp_dir.path())?)?)
+}
+
+fn check_binary<E: KvsEngine>(engine: E) -> Result<()> {
+    let key = vec![0x00, 0xff, 0xfe, b'k'];
+    let value = vec![0x80, 0x00, 0xc3, 0x28];
+    engine.set_bytes(key.clone(), value.clone())?;
+    assert_eq!(engine.get_bytes(key.clone())?, Some(value.clone()));
+    assert_eq!(engine.get_bytes(vec![0xff])?, None);
+
+    // the string API is a view of the same keys
+    engine.set("text".to_owned(), "value".to_owned())?;
+    assert_eq!(engine.get_bytes(b"text".to_vec())?, Some(b"value".to_vec()));
+    engine.set_bytes(b"blob".to_vec(), value.clone())?;
+    assert!(matches!(
+        engine.get("blob".to_owned()),
+        Err(KvsError::Utf8Error(_))
+    ));
+
+    let mut batch = WriteBatch::new();
+    batch.set_bytes(vec![0xfd], vec![0xfc]);
+    batch.remove_bytes(b"blob".to_vec());
+    engine.write_batch(batch)?;
+    assert_eq!(engine.get_bytes(vec![0xfd])?, Some(vec![0xfc]));
+    assert_eq!(engine.get_bytes(b"blob".to_vec())?, None);
+
+    engine.remove_bytes(vec![0xfd])?;
+    assert!(matches!(
+        engine.remove_bytes(vec![0xfd]),
+        Err(KvsError::KeyNotFound(_))
+    ));
+
+    engine.set_with_ttl_bytes(vec![0xfb], vec![0xfa], Duration::from_millis(50))?;
+    assert_eq!(engine.get_bytes(vec![0xfb])?, Some(vec![0xfa]));
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(engine.get_bytes(vec![0xfb])?, None);
+
+    assert!(!engine.compare_and_swap_bytes(key.clone(), Some(vec![0x80]), None)?);
+    assert!(engine.compare_and_swap_bytes(
+        key.clone(),
+        Some(value.clone()),
+        Some(vec![0xff, 0xff])
+    )?);
+    assert!(engine.compare_and_swap_bytes(vec![0xff, 0x01], None, Some(vec![0x81]))?);
+
+    // binary keys are scanned in the order of their bytes
+    let scan = |range: (Bound<Vec<u8>>, Bound<Vec<u8>>)| -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        engine.scan_bytes(range, ScanOptions::new())?.collect()
+    };
+    assert_eq!(
+        scan((Bound::Unbounded, Bound::Unbounded))?,
+        vec![
+            (key.clone(), vec![0xff, 0xff]),
+            (b"text".to_vec(), b"value".to_vec()),
+            (vec![0xff, 0x01], vec![0x81]),
+        ]
+    );
+    assert_eq!(
+        scan((
+            Bound::Excluded(key.clone()),
+            Bound::Included(b"text".to_vec())
+        ))?,
+        vec![(b"text".to_vec(), b"value".to_vec())]
+    );
+    let prefixed: Vec<_> = engine
+        .scan_prefix_bytes(vec![0xff], ScanOptions::new())?
+        .collect::<Result<_>>()?;
+    assert_eq!(prefixed, vec![(vec![0xff, 0x01], vec![0x81])]);
+    // the string scan fails the pairs that are not UTF-8 but goes on
+    let items: Vec<_> = engine.scan(.., ScanOptions::new())?.collect();
+    assert!(matches!(items[0], Err(KvsError::Utf8Error(_))));
+    assert_eq!(
+        items[1].as_ref().ok(),
+        Some(&("text".to_owned(), "value".to_owned()))
+    );
+    assert_eq!(items.len(), 3);
+
+    let mut tx = engine.transaction();
+    assert_eq!(tx.get_bytes(vec![0xff, 0x01])?, Some(vec![0x81]));
+    tx.set_bytes(vec![0xff, 0x01], vec![0x82]);
+    tx.remove_bytes(key.clone());
+    assert_eq!(tx.get_bytes(vec![0xff, 0x01])?, Some(vec![0x82]));
+    tx.commit()?;
+    assert_eq!(engine.get_bytes(vec![0xff, 0x01])?, Some(vec![0x82]));
+    assert_eq!(engine.get_bytes(key.clone())?, None);
+
+    let mut tx = engine.transaction();
+    tx.get_bytes(vec![0xff, 0x01])?;
+    engine.set_bytes(vec![0xff, 0x01], vec![0x83])?;
+    tx.set_bytes(vec![0xff, 0x01], vec![0x84]);
+    assert!(matches!(tx.commit(), Err(KvsError::TransactionConflict(_))));
+    engine.set_bytes(key, value)?;
+    Ok(())
+}
+
+#[test]
+fn binary_kvs_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_binary(KvStore::open(temp_dir.path())?)?;
+
+    // non-UTF-8 keys and values are read back from the log
+    let key = vec![0x00, 0xff, 0xfe, b'k'];
+    let value = vec![0x80, 0x00, 0xc3, 0x28];
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(key)?, Some(value));
+    assert_eq!(store.get("text".to_owned())?, Some("value".to_owned()));
+
+    let snapshot = store.snapshot()?;
+    store.set_bytes(vec![0xff, 0x02], vec![0x85])?;
+    let prefixed: Vec<_> = snapshot
+        .scan_prefix_bytes(vec![0xff], ScanOptions::new())?
+        .collect::<Result<_>>()?;
+    assert_eq!(prefixed, vec![(vec![0xff, 0x01], vec![0x83])]);
+    Ok(())
+}
+
+#[test]
+fn binary_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_binary(SledKvsEngine::new(sled::open(temp_dir.path())?)?)
+}
+
+fn check_ttl<E: KvsEngine>(engine: E) -> Result<()> {
+    let ttl = Duration::from_millis(100);
+    engine.set_with_ttl("short".to_owned(), "v1".to_owned(), ttl)?;
+    engine.set_with_ttl(
+        "long".to_owned(),
+        "v2".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    engine.set_with_ttl("reset".to_owned(), "v3".to_owned(), ttl)?;
+    engine.set("reset".to_owned(), "v4".to_owned())?;
+    assert_eq!(engine.get("short".to_owned())?, Some("v1".to_owned()));
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(engine.get("short".to_owned())?, None);
+    assert_eq!(engine.get("long".to_owned())?, Some("v2".to_owned()));
+    assert_eq!(engine.get("reset".to_owned())?, Some("v4".to_owned()));
+    let scan = engine.scan(.., ScanOptions::new())?;
+    assert_eq!(scan_keys(scan)?, ["long", "reset"]);
+    assert!(matches!(
+        engine.remove("short".to_owned()),
+        Err(KvsError::KeyNotFound(_))
+    ));
+
+    // an expired key can be set again
+    assert!(engine.set_if_absent("short".to_owned(), "v5".to_owned())?);
+    assert_eq!(engine.get("short".to_owned())?, Some("v5".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn ttl_kvs_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_ttl(KvStore::open(temp_dir.path())?)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("short".to_owned())?, Some("v5".to_owned()));
+    assert_eq!(store.get("long".to_owned())?, Some("v2".to_owned()));
+    assert_eq!(store.get("reset".to_owned())?, Some("v4".to_owned()));
+
+    // keys expiring while the store is closed are gone once it is reopened
+    store.set_with_ttl(
+        "closed".to_owned(),
+        "v6".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    drop(store);
+    thread::sleep(Duration::from_millis(200));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("closed".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn ttl_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_ttl(SledKvsEngine::new(sled::open(temp_dir.path())?)?)
+}
+
+// Expired keys should be removed in the background and compacted away.
+#[test]
+fn reap_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_trigger(CompactionTrigger::StaleBytes(64 * 1024))
+        .max_segment_size(4 * 1024)
+        .reap_interval(Duration::from_millis(50));
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for key_id in 0..100 {
+        store.set_with_ttl(
+            format!("key{}", key_id),
+            "0".repeat(1000),
+            Duration::from_millis(100),
+        )?;
+    }
+    store.set("kept".to_owned(), "value".to_owned())?;
+    // wait for the reaper and the background compaction
+    thread::sleep(Duration::from_millis(1000));
+    drop(store);
+
+    let total: u64 = log_sizes(temp_dir.path()).iter().sum();
+    assert!(total < 64 * 1024, "expired entries not compacted");
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, None);
+    }
+    assert_eq!(store.get("kept".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Compaction should leave a hint file behind, and the store should reopen from it.
+// A damaged hint file should be ignored in favor of its log.
+#[test]
+fn reopen_from_hint_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let hint_files = || {
+        fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension() == Some("hint".as_ref()))
+            .collect::<Vec<_>>()
+    };
+
+    // never overwritten, so every compaction has a live entry to copy
+    store.set("cold".to_owned(), "value".to_owned())?;
+    let mut iter = 0;
+    while hint_files().is_empty() {
+        assert!(iter < 1000, "No compaction detected");
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        iter += 1;
+    }
+    store.remove("key0".to_owned())?;
+    drop(store);
+
+    let check = || -> Result<()> {
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("cold".to_owned())?, Some("value".to_owned()));
+        assert_eq!(store.get("key0".to_owned())?, None);
+        for key_id in 1..1000 {
+            let key = format!("key{}", key_id);
+            assert_eq!(store.get(key)?, Some(format!("{}", iter - 1)));
+        }
+        Ok(())
+    };
+    check()?;
+
+    for hint in hint_files() {
+        fs::write(hint, b"garbage")?;
+    }
+    check()
+}
+
+// Dropping the store in the middle of a background compaction should not leave a
+// half-written compaction file behind, and no data should be lost.
+#[test]
+fn shutdown_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let value = "v".repeat(10 * 1024);
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..3 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}{}", value, iter))?;
+        }
+    }
+    drop(store);
+
+    for entry in fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        assert_ne!(
+            path.extension(),
+            Some("compact".as_ref()),
+            "{:?} left behind",
+            path
+        );
+    }
+
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        let key = format!("key{}", key_id);
+        assert_eq!(store.get(key)?, Some(format!("{}2", value)));
+    }
+    Ok(())
+}
+
+// A compaction file left behind by a crash should be removed when the store is opened.
+#[test]
+fn remove_interrupted_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let leftover = temp_dir.path().join("2.compact");
+    fs::write(&leftover, b"half-written compaction")?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!leftover.exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn open_with_options() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("store");
+
+    let no_create = KvStoreOptions::new().create_if_missing(false);
+    assert!(KvStore::open_with(&path, no_create).is_err());
+
+    let options = KvStoreOptions::new()
+        .read_buffer_size(64)
+        .write_buffer_size(64)
+        .durability(Durability::Always)
+        .error_if_exists(true);
+    let store = KvStore::open_with(&path, options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    assert!(KvStore::open_with(&path, options).is_err());
+    let store = KvStore::open_with(&path, KvStoreOptions::new().create_if_missing(false))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let invalid = KvStoreOptions::new().compaction_trigger(CompactionTrigger::StaleRatio(0.0));
+    match KvStore::open_with(&path, invalid) {
+        Err(KvsError::InvalidOption(_)) => Ok(()),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("invalid options were accepted"),
+    }
+}
+
+/// Returns the sizes of the log files in `dir`.
+fn log_sizes(dir: &Path) -> Vec<u64> {
+    fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("log".as_ref()))
+        .map(|path| fs::metadata(path).unwrap().len())
+        .collect()
+}
+
+// Should roll to a new log file once the active one is full
+#[test]
+fn rotate_full_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_trigger(CompactionTrigger::StaleBytes(u64::MAX))
+        .max_segment_size(4 * 1024);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), "v".repeat(100))?;
+    }
+    drop(store);
+
+    let sizes = log_sizes(temp_dir.path());
+    assert!(sizes.len() > 20, "only {} log files", sizes.len());
+    for size in sizes {
+        // a segment ends with the record that made it reach the limit
+        assert!(size < 4 * 1024 + 200, "log file of {} bytes", size);
+    }
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..1000 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("v".repeat(100)));
+    }
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), "w".repeat(100))?;
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("w".repeat(100)));
+    }
+    Ok(())
+}
+
+// Should compact the entries spread over many segments
+#[test]
+fn compact_many_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_trigger(CompactionTrigger::StaleBytes(64 * 1024))
+        .max_segment_size(4 * 1024);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter).repeat(100))?;
+        }
+    }
+    // wait for the background compaction
+    thread::sleep(Duration::from_millis(500));
+    drop(store);
+
+    let total: u64 = log_sizes(temp_dir.path()).iter().sum();
+    assert!(total < 100 * 1024, "stale entries not compacted");
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("9".repeat(100)));
+    }
+    Ok(())
+}
+
+// Should only compact the segments holding mostly stale entries
+#[test]
+fn compact_stale_segments_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_trigger(CompactionTrigger::StaleBytes(8 * 1024))
+        .max_segment_size(4 * 1024);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    store.set("gone".to_owned(), "value".to_owned())?;
+    for key_id in 0..200 {
+        store.set(format!("cold{}", key_id), "c".repeat(100))?;
+    }
+    let cold_gens = log_sizes(temp_dir.path()).len() as u64 - 1;
+    for iter in 0..50 {
+        if iter == 25 {
+            store.remove("gone".to_owned())?;
+        }
+        for key_id in 0..10 {
+            store.set(format!("hot{}", key_id), format!("{}", iter).repeat(100))?;
+        }
+    }
+    // wait for the background compaction
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(store.get("gone".to_owned())?, None);
+    drop(store);
+
+    // the segments holding only live entries are left in place
+    for gen in 1..=cold_gens {
+        assert!(temp_dir.path().join(format!("{}.log", gen)).exists());
+    }
+    let newest_gen = fs::read_dir(temp_dir.path())?
+        .filter_map(|entry| {
+            let path = entry.unwrap().path();
+            path.file_stem()?.to_str()?.parse::<u64>().ok()
+        })
+        .max()
+        .unwrap();
+    assert!(
+        (log_sizes(temp_dir.path()).len() as u64) < newest_gen,
+        "stale segments not compacted"
+    );
+
+    // reopen twice to load the compaction generation from its log and its hint
+    for _ in 0..2 {
+        let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+        for key_id in 0..200 {
+            assert_eq!(store.get(format!("cold{}", key_id))?, Some("c".repeat(100)));
+        }
+        for key_id in 0..10 {
+            assert_eq!(store.get(format!("hot{}", key_id))?, Some("49".repeat(100)));
+        }
+        // the remove still hides the value in the first segment
+        assert_eq!(store.get("gone".to_owned())?, None);
+    }
+    Ok(())
+}
+
+// A rate limited compaction should speed up once the limit is lifted
+#[test]
+fn compaction_rate_limit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_trigger(CompactionTrigger::StaleBytes(64 * 1024))
+        .compaction_rate_limit(16 * 1024);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert!(store.set_compaction_rate_limit(Some(0)).is_err());
+
+    let hint_exists = || {
+        fs::read_dir(temp_dir.path())
+            .unwrap()
+            .any(|entry| entry.unwrap().path().extension() == Some("hint".as_ref()))
+    };
+    store.set("cold".to_owned(), "value".to_owned())?;
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter).repeat(100))?;
+        }
+    }
+    // copying the victims takes several seconds at 16 KiB/s
+    thread::sleep(Duration::from_millis(500));
+    assert!(!hint_exists(), "compaction ignored the rate limit");
+
+    store.set_compaction_rate_limit(None)?;
+    let mut waited = 0;
+    while !hint_exists() {
+        assert!(
+            waited < 20,
+            "compaction did not finish after lifting the limit"
+        );
+        thread::sleep(Duration::from_millis(100));
+        waited += 1;
+    }
+    assert_eq!(store.get("cold".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("key0".to_owned())?, Some("9".repeat(100)));
+    Ok(())
+}
+
+// Repeated gets should be answered from the read cache
+#[test]
+fn read_cache_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    for _ in 0..3 {
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    }
+    let stats = store.clone().stats();
+    assert_eq!(stats.cache_misses, 1);
+    assert_eq!(stats.cache_hits, 2);
+
+    // an overwrite moves the key to a record that is not cached yet
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(
+        store.stats(),
+        KvStoreStats {
+            cache_hits: 3,
+            cache_misses: 2,
+            value_bytes: 12,
+            stored_value_bytes: 12,
+        }
+    );
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().cache_size(0))?;
+    for _ in 0..3 {
+        assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    }
+    assert_eq!(store.stats().cache_hits, 0);
+    assert_eq!(store.stats().cache_misses, 3);
+    Ok(())
+}
+
+/// Returns the total size of the log files in `dir`.
+fn log_size(dir: &Path) -> u64 {
+    log_sizes(dir).iter().sum()
+}
+
+fn json_document(id: usize) -> String {
+    let items: Vec<String> = (0..50)
+        .map(|i| format!(r#"{{"id":{},"name":"item {}","tags":["a","b"]}}"#, id, i))
+        .collect();
+    format!("[{}]", items.join(","))
+}
+
+// Large values should be compressed in the log and read back transparently
+#[test]
+fn compressed_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compression(Compression::Deflate);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for id in 0..100 {
+        store.set(format!("doc{}", id), json_document(id))?;
+    }
+    // below the threshold, stored as it is
+    store.set("small".to_owned(), "value".to_owned())?;
+    for id in 0..100 {
+        assert_eq!(store.get(format!("doc{}", id))?, Some(json_document(id)));
+    }
+
+    let raw_size: u64 = (0..100).map(|id| json_document(id).len() as u64).sum();
+    assert!(log_size(temp_dir.path()) < raw_size / 4);
+    let stats = store.stats();
+    assert_eq!(stats.value_bytes, raw_size + 5);
+    assert!(stats.compression_ratio() > 4.0);
+    drop(store);
+
+    // the codec is read from every record, whatever the store is opened with
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("doc7".to_owned())?, Some(json_document(7)));
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.stats().compression_ratio(), 1.0);
+    Ok(())
+}
+
+// A compaction should rewrite every value with the codec the store is opened with
+#[test]
+fn recompress_on_compact() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for id in 0..100 {
+        store.set(format!("doc{}", id), json_document(id))?;
+    }
+    drop(store);
+    let uncompressed = log_size(temp_dir.path());
+
+    let check = |store: &KvStore| -> Result<()> {
+        for id in 0..100 {
+            assert_eq!(store.get(format!("doc{}", id))?, Some(json_document(id)));
+        }
+        Ok(())
+    };
+
+    let options = KvStoreOptions::new().compression(Compression::Snappy);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.compact()?;
+    check(&store)?;
+    drop(store);
+    let snappy = log_size(temp_dir.path());
+    assert!(snappy < uncompressed / 2);
+
+    let options = KvStoreOptions::new().compression(Compression::Deflate);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.compact()?;
+    check(&store)?;
+    drop(store);
+    assert!(log_size(temp_dir.path()) < snappy);
+
+    // and back to plain values
+    let store = KvStore::open(temp_dir.path())?;
+    store.compact()?;
+    check(&store)?;
+    drop(store);
+    assert!(log_size(temp_dir.path()) >= uncompressed - 1024);
+    check(&KvStore::open(temp_dir.path())?)
+}
+
+/// Returns the bytes of all the log and hint files in `dir`.
+fn store_bytes(dir: &Path) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for entry in WalkDir::new(dir).into_iter().flatten() {
+        let path = entry.path();
+        if path.extension() == Some("log".as_ref()) || path.extension() == Some("hint".as_ref()) {
+            bytes.extend(fs::read(path).expect("unable to read a store file"));
+        }
+    }
+    bytes
+}
+
+fn contains(haystack: &[u8], needle: &str) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle.as_bytes())
+}
+
+// Keys and values should never reach the disk in the clear, and should be read
+// back from the log, from memory maps and from hint files
+#[test]
+fn encrypted_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let plain_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key = EncryptionKey::new([7; 32]);
+    let plain_options = KvStoreOptions::new()
+        .compression(Compression::Snappy)
+        .mmap_reads(true)
+        .cache_size(0)
+        .max_segment_size(1024);
+    let options = plain_options.clone().encryption_key(key.clone());
+    let fill = |store: &KvStore| -> Result<()> {
+        for id in 0..100 {
+            store.set(format!("secret-key{}", id), format!("secret-value{}", id))?;
+        }
+        let mut batch = WriteBatch::new();
+        batch.set("secret-key0".to_owned(), json_document(0));
+        batch.remove("secret-key1".to_owned());
+        store.write_batch(batch)?;
+        store.remove("secret-key2".to_owned())
+    };
+    // strings the store holds, the last one a field of the document
+    let secrets = ["secret-key5", "secret-value5", r#""name":"item 0""#];
+
+    // the same writes leave the strings in the files of a plain store
+    fill(&KvStore::open_with(plain_dir.path(), plain_options)?)?;
+    let bytes = store_bytes(plain_dir.path());
+    assert!(secrets.iter().all(|secret| contains(&bytes, secret)));
+
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    fill(&store)?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("secret-key0".to_owned())?, Some(json_document(0)));
+        assert_eq!(store.get("secret-key1".to_owned())?, None);
+        assert_eq!(store.get("secret-key2".to_owned())?, None);
+        for id in 3..100 {
+            assert_eq!(
+                store.get(format!("secret-key{}", id))?,
+                Some(format!("secret-value{}", id))
+            );
+        }
+        Ok(())
+    };
+    check(&store)?;
+    let bytes = store_bytes(temp_dir.path());
+    assert!(!contains(&bytes, "secret"));
+    assert!(!secrets.iter().any(|secret| contains(&bytes, secret)));
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    check(&store)?;
+    store.compact()?;
+    check(&store)?;
+    drop(store);
+    let bytes = store_bytes(temp_dir.path());
+    assert!(!secrets.iter().any(|secret| contains(&bytes, secret)));
+
+    // reopened from the hint file of the compaction
+    check(&KvStore::open_with(temp_dir.path(), options)?)
+}
+
+/// Adds one to the sequence number of the first record of the log file of
+/// generation 1 in `dir`, whose header is `header_len` bytes long, and fixes
+/// up the checksum of the record.
+fn bump_first_seq(dir: &Path, header_len: usize) {
+    let path = dir.join("1.log");
+    let mut log = fs::read(&path).expect("unable to read the log file");
+    let record = &mut log[header_len..];
+    let len = u32::from_le_bytes([record[4], record[5], record[6], record[7]]) as usize;
+    record[9] += 1;
+    let crc = crc32fast::hash(&record[4..9 + len]);
+    record[..4].copy_from_slice(&crc.to_le_bytes());
+    fs::write(&path, &log).expect("unable to write the log file");
+}
+
+// Rewriting the sequence number of an encrypted record, checksum included,
+// should be detected
+#[test]
+fn tamper_with_sequence_number() -> Result<()> {
+    let write = |store: &KvStore, batch: bool| -> Result<()> {
+        if batch {
+            let mut batch = WriteBatch::new();
+            batch.set("key1".to_owned(), "value1".to_owned());
+            batch.set("key2".to_owned(), "value2".to_owned());
+            store.write_batch(batch)?;
+        } else {
+            store.set("key1".to_owned(), "value1".to_owned())?;
+        }
+        store.set("key3".to_owned(), "value3".to_owned())
+    };
+    for batch in [false, true] {
+        // a plain store only has the checksum, which is fixed up
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        write(&KvStore::open(temp_dir.path())?, batch)?;
+        bump_first_seq(temp_dir.path(), 9);
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+        // magic, version, cipher byte, file nonce and key check tag
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::new().encryption_key(EncryptionKey::new([3; 32]));
+        write(
+            &KvStore::open_with(temp_dir.path(), options.clone())?,
+            batch,
+        )?;
+        bump_first_seq(temp_dir.path(), 41);
+        match KvStore::open_with(temp_dir.path(), options) {
+            Err(KvsError::Corruption { gen: 1, pos: 41 }) => {}
+            res => panic!("unexpected result {:?}", res.map(|_| ())),
+        }
+    }
+    Ok(())
+}
+
+// Opening a log file of another format version should fail
+#[test]
+fn open_unsupported_version() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    let path = temp_dir.path().join("1.log");
+    let mut log = fs::read(&path)?;
+    log[4..8].copy_from_slice(&2u32.to_le_bytes());
+    fs::write(&path, log)?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::UnsupportedVersion(2)) => {}
+        res => panic!("unexpected result {:?}", res.map(|_| ())),
+    }
+    Ok(())
+}
+
+// Opening an encrypted store without its key should fail rather than lose data
+#[test]
+fn open_with_wrong_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().encryption_key(EncryptionKey::new([1; 32]));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    let options = KvStoreOptions::new().encryption_key(EncryptionKey::new([2; 32]));
+    assert!(matches!(
+        KvStore::open_with(temp_dir.path(), options),
+        Err(KvsError::WrongKey { .. })
+    ));
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::WrongKey { .. })
+    ));
+    Ok(())
+}
+
+// A compaction should move every record to the new key, after which the old
+// key is no longer needed
+#[test]
+fn rotate_encryption_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let old_key = EncryptionKey::new([1; 32]);
+    let new_key = EncryptionKey::new([2; 32]);
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::new().encryption_key(old_key.clone()),
+    )?;
+    for id in 0..100 {
+        store.set(format!("key{}", id), format!("value{}", id))?;
+    }
+    drop(store);
+
+    let options = KvStoreOptions::new()
+        .encryption_key(new_key.clone())
+        .old_encryption_key(old_key.clone());
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key0".to_owned(), "new".to_owned())?;
+    store.compact()?;
+    drop(store);
+
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::new().encryption_key(new_key),
+    )?;
+    assert_eq!(store.get("key0".to_owned())?, Some("new".to_owned()));
+    for id in 1..100 {
+        assert_eq!(
+            store.get(format!("key{}", id))?,
+            Some(format!("value{}", id))
+        );
+    }
+    drop(store);
+    assert!(matches!(
+        KvStore::open_with(
+            temp_dir.path(),
+            KvStoreOptions::new().encryption_key(old_key)
+        ),
+        Err(KvsError::WrongKey { .. })
+    ));
+    Ok(())
+}
+
+// A store written in the clear should be encrypted by compacting it with a key
+#[test]
+fn encrypt_plain_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for id in 0..100 {
+        store.set(format!("secret-key{}", id), format!("secret-value{}", id))?;
+    }
+    drop(store);
+    assert!(contains(&store_bytes(temp_dir.path()), "secret"));
+
+    let options = KvStoreOptions::new().encryption_key(EncryptionKey::new([3; 32]));
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    assert_eq!(
+        store.get("secret-key7".to_owned())?,
+        Some("secret-value7".to_owned())
+    );
+    store.compact()?;
+    drop(store);
+    assert!(!contains(&store_bytes(temp_dir.path()), "secret"));
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for id in 0..100 {
+        assert_eq!(
+            store.get(format!("secret-key{}", id))?,
+            Some(format!("secret-value{}", id))
+        );
+    }
+    Ok(())
+}
+
+// Keys should be read from hex strings and files
+#[test]
+fn parse_encryption_key() -> Result<()> {
+    let hex = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    let mut raw = [0; 32];
+    for (i, byte) in raw.iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+    assert_eq!(EncryptionKey::from_hex(hex)?, EncryptionKey::new(raw));
+    assert!(EncryptionKey::from_hex("0011").is_err());
+    assert!(EncryptionKey::from_hex(&"zz".repeat(32)).is_err());
+    assert_eq!(
+        format!("{:?}", EncryptionKey::new(raw)),
+        "EncryptionKey(..)"
+    );
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let hex_file = temp_dir.path().join("hex");
+    fs::write(&hex_file, format!("{}\n", hex))?;
+    assert_eq!(
+        EncryptionKey::from_file(&hex_file)?,
+        EncryptionKey::new(raw)
+    );
+    let raw_file = temp_dir.path().join("raw");
+    fs::write(&raw_file, raw)?;
+    assert_eq!(
+        EncryptionKey::from_file(&raw_file)?,
+        EncryptionKey::new(raw)
+    );
+    Ok(())
+}
+
+// A checkpoint taken while a writer overwrites keys and compactions run should
+// hold a consistent prefix of the writes, and nothing written after it
+#[test]
+fn checkpoint_during_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let checkpoint_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_trigger(CompactionTrigger::StaleBytes(16 * 1024))
+        .max_segment_size(8 * 1024);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "0".to_owned())?;
+    }
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for round in 1..50 {
+                for key_id in 0..100 {
+                    store.set(format!("key{}", key_id), format!("{}", round))?;
+                }
+            }
+            Ok(())
+        })
+    };
+    thread::sleep(Duration::from_millis(50));
+    store.checkpoint(checkpoint_dir.path())?;
+    writer.join().unwrap()?;
+    store.set("after".to_owned(), "checkpoint".to_owned())?;
+
+    let copy = KvStore::open(checkpoint_dir.path())?;
+    assert_eq!(copy.get("after".to_owned())?, None);
+    let rounds: Vec<u32> = (0..100)
+        .map(|key_id| {
+            let value = copy.get(format!("key{}", key_id))?.expect("key is missing");
+            Ok(value.parse().unwrap())
+        })
+        .collect::<Result<_>>()?;
+    // the keys are written in order, so the last round reached some key and
+    // the round before it reached all the later ones
+    for pair in rounds.windows(2) {
+        assert!(pair[0] == pair[1] || pair[0] == pair[1] + 1, "{:?}", rounds);
+    }
+
+    // the copy is a store of its own
+    copy.set("key0".to_owned(), "copy".to_owned())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("49".to_owned()));
+    Ok(())
+}
+
+// A checkpoint should copy hint files, keep the encryption of the store and
+// refuse to overwrite another store
+#[test]
+fn checkpoint_compacted_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let checkpoint_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().encryption_key(EncryptionKey::new([5; 32]));
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key0".to_owned())?;
+    store.compact()?;
+    store.set("key1".to_owned(), "new".to_owned())?;
+    store.checkpoint(checkpoint_dir.path())?;
+
+    let hints = fs::read_dir(checkpoint_dir.path())?
+        .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("hint".as_ref()))
+        .count();
+    assert_eq!(hints, 1);
+    assert!(matches!(
+        KvStore::open(checkpoint_dir.path()),
+        Err(KvsError::WrongKey { .. })
+    ));
+    let copy = KvStore::open_with(checkpoint_dir.path(), options)?;
+    assert_eq!(copy.get("key0".to_owned())?, None);
+    assert_eq!(copy.get("key1".to_owned())?, Some("new".to_owned()));
+    for key_id in 2..100 {
+        assert_eq!(
+            copy.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    drop(copy);
+
+    assert!(store.checkpoint(checkpoint_dir.path()).is_err());
+    Ok(())
+}
+
+#[test]
+fn checkpoint_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let checkpoint_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::new(sled::open(temp_dir.path())?)?;
+    for key_id in 0..100 {
+        engine.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    engine.set_with_ttl(
+        "expiring".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    engine.checkpoint(checkpoint_dir.path())?;
+    engine.set("after".to_owned(), "checkpoint".to_owned())?;
+    assert!(engine.checkpoint(checkpoint_dir.path()).is_err());
+
+    let copy = SledKvsEngine::new(sled::open(checkpoint_dir.path())?)?;
+    assert_eq!(copy.get("after".to_owned())?, None);
+    for key_id in 0..100 {
+        assert_eq!(
+            copy.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    // the expiry time is copied too
+    assert_eq!(copy.get("expiring".to_owned())?, Some("value".to_owned()));
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(copy.get("expiring".to_owned())?, None);
+    Ok(())
+}
+
+// A sled checkpoint should hold either all or none of the writes of a batch
+#[test]
+fn checkpoint_sled_engine_during_batches() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let checkpoint_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::with_durability(sled::open(temp_dir.path())?, Durability::Never)?;
+    let write_round = |engine: &SledKvsEngine, round: u32| {
+        let mut batch = WriteBatch::new();
+        for key_id in 0..100 {
+            batch.set(format!("key{}", key_id), format!("{}", round));
+        }
+        engine.write_batch(batch)
+    };
+    write_round(&engine, 0)?;
+
+    let writer = {
+        let engine = engine.clone();
+        thread::spawn(move || -> Result<()> {
+            for round in 1..200 {
+                write_round(&engine, round)?;
+            }
+            Ok(())
+        })
+    };
+    thread::sleep(Duration::from_millis(20));
+    engine.checkpoint(checkpoint_dir.path())?;
+    writer.join().unwrap()?;
+    drop(engine);
+
+    let copy = SledKvsEngine::new(sled::open(checkpoint_dir.path())?)?;
+    let round = copy.get("key0".to_owned())?;
+    for key_id in 1..100 {
+        assert_eq!(copy.get(format!("key{}", key_id))?, round);
+    }
+    Ok(())
+}
+
+/// Returns pairs whose keys and values need quoting or escaping in a dump.
+fn dump_pairs() -> Vec<(String, String)> {
+    let mut pairs: Vec<(String, String)> = (0..250)
+        .map(|id| (format!("key{:03}", id), format!("value{}", id)))
+        .collect();
+    pairs.push(("comma,key".to_owned(), "a, b and \"c\"".to_owned()));
+    pairs.push((
+        "line\nbreak".to_owned(),
+        "{\"json\": [1, 2]}\r\n".to_owned(),
+    ));
+    pairs.push(("ünïcødé".to_owned(), "日本語".to_owned()));
+    pairs.push(("empty".to_owned(), String::new()));
+    pairs.sort();
+    pairs
+}
+
+// Exporting from one engine and importing into another should copy every pair,
+// whatever the format
+fn check_dump<E: KvsEngine, F: KvsEngine>(source: &E, dest: &F, format: DumpFormat) -> Result<()> {
+    let mut pairs: Vec<(Vec<u8>, Vec<u8>)> = dump_pairs()
+        .into_iter()
+        .map(|(key, value)| (key.into_bytes(), value.into_bytes()))
+        .collect();
+    // pairs that are not UTF-8 are dumped in base64
+    pairs.push((vec![0x00, 0xff, 0xfe], vec![0x80, 0x00]));
+    pairs.push((b"binary value".to_vec(), vec![0xc3, 0x28]));
+    pairs.sort();
+    for (key, value) in &pairs {
+        source.set_bytes(key.clone(), value.clone())?;
+    }
+    source.set("removed".to_owned(), "value".to_owned())?;
+    source.remove("removed".to_owned())?;
+
+    let mut dump = Vec::new();
+    assert_eq!(kvs::export(source, &mut dump, format)?, pairs.len() as u64);
+
+    let mut progress = Vec::new();
+    let options = ImportOptions::new().format(format).batch_size(100);
+    let count = kvs::import(dest, dump.as_slice(), options, |count| progress.push(count))?;
+    assert_eq!(count, pairs.len() as u64);
+    assert_eq!(progress, vec![100, 200, pairs.len() as u64]);
+
+    let imported: Vec<(Vec<u8>, Vec<u8>)> = dest
+        .scan_bytes(.., ScanOptions::new())?
+        .collect::<Result<_>>()?;
+    assert_eq!(imported, pairs);
+
+    // a dump of the copy is the same dump
+    let mut copy = Vec::new();
+    kvs::export(dest, &mut copy, format)?;
+    assert_eq!(copy, dump);
+    Ok(())
+}
+
+#[test]
+fn dump_kvs_to_sled() -> Result<()> {
+    for format in [DumpFormat::JsonLines, DumpFormat::Csv] {
+        let source_dir = TempDir::new().expect("unable to create temporary working directory");
+        let dest_dir = TempDir::new().expect("unable to create temporary working directory");
+        check_dump(
+            &KvStore::open(source_dir.path())?,
+            &SledKvsEngine::new(sled::open(dest_dir.path())?)?,
+            format,
+        )?;
+    }
+    Ok(())
+}
+
+#[test]
+fn dump_sled_to_kvs() -> Result<()> {
+    for format in [DumpFormat::JsonLines, DumpFormat::Csv] {
+        let source_dir = TempDir::new().expect("unable to create temporary working directory");
+        let dest_dir = TempDir::new().expect("unable to create temporary working directory");
+        check_dump(
+            &SledKvsEngine::new(sled::open(source_dir.path())?)?,
+            &KvStore::open(dest_dir.path())?,
+            format,
+        )?;
+    }
+    Ok(())
+}
+
+// A malformed dump should fail with its line, after importing the batches
+// before it
+#[test]
+fn import_malformed_dump() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let dump = "{\"key\": \"a\", \"value\": \"1\"}\n\n{\"key\": \"b\"}\n";
+    let options = ImportOptions::new().batch_size(1);
+    match kvs::import(&store, dump.as_bytes(), options, |_| {}) {
+        Err(KvsError::InvalidDump { line, .. }) => assert_eq!(line, 3),
+        res => panic!("unexpected result {:?}", res),
+    }
+    assert_eq!(store.get("a".to_owned())?, Some("1".to_owned()));
+    assert_eq!(store.get("b".to_owned())?, None);
+
+    let dump = "key,value\nc,3\nd\n";
+    let options = options.format(DumpFormat::Csv);
+    match kvs::import(&store, dump.as_bytes(), options, |_| {}) {
+        Err(KvsError::InvalidDump { line, .. }) => assert_eq!(line, 3),
+        res => panic!("unexpected result {:?}", res),
+    }
+    assert_eq!(store.get("c".to_owned())?, Some("3".to_owned()));
+    assert_eq!("xml".parse::<DumpFormat>().ok(), None);
+
+    let dump = "{\"key\": \"ZQ==\", \"value\": \"not base64\", \"encoding\": \"base64\"}\n";
+    match kvs::import(&store, dump.as_bytes(), ImportOptions::new(), |_| {}) {
+        Err(KvsError::InvalidDump { line, .. }) => assert_eq!(line, 1),
+        res => panic!("unexpected result {:?}", res),
+    }
+    let dump = "key,value,encoding\ne,5,\n/w==,gA==,base64\nf,6,base32\n";
+    match kvs::import(&store, dump.as_bytes(), options, |_| {}) {
+        Err(KvsError::InvalidDump { line, .. }) => assert_eq!(line, 4),
+        res => panic!("unexpected result {:?}", res),
+    }
+    assert_eq!(store.get("e".to_owned())?, Some("5".to_owned()));
+    assert_eq!(store.get_bytes(vec![0xff])?, Some(vec![0x80]));
+    Ok(())
+}
+
+// Keys of different keyspaces should not see each other
+#[test]
+fn isolate_keyspaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let users = store.create_keyspace("users")?;
+    let orders = store.create_keyspace("orders")?;
+    assert_eq!(store.list_keyspaces(), vec!["orders", "users"]);
+
+    store.set("key".to_owned(), "default".to_owned())?;
+    users.set("key".to_owned(), "user".to_owned())?;
+    assert_eq!(store.get("key".to_owned())?, Some("default".to_owned()));
+    assert_eq!(users.get("key".to_owned())?, Some("user".to_owned()));
+    assert_eq!(orders.get("key".to_owned())?, None);
+    assert!(matches!(
+        orders.remove("key".to_owned()),
+        Err(KvsError::KeyNotFound(_))
+    ));
+
+    let mut batch = WriteBatch::new();
+    batch.set("a".to_owned(), "1".to_owned());
+    batch.remove("key".to_owned());
+    users.write_batch(batch)?;
+    assert_eq!(users.get("key".to_owned())?, None);
+    assert_eq!(store.get("key".to_owned())?, Some("default".to_owned()));
+    assert_eq!(
+        scan_keys(users.scan(.., ScanOptions::new())?)?,
+        vec!["a".to_owned()]
+    );
+
+    assert!(matches!(
+        store.create_keyspace("users"),
+        Err(KvsError::KeyspaceExists(_))
+    ));
+    assert!(matches!(
+        store.create_keyspace("__internal"),
+        Err(KvsError::InvalidKeyspaceName(_))
+    ));
+    assert!(matches!(
+        store.keyspace("missing"),
+        Err(KvsError::KeyspaceNotFound(_))
+    ));
+    assert_eq!(users.keyspace("orders")?.get("key".to_owned())?, None);
+    Ok(())
+}
+
+// Keyspaces should survive reopening the store, also from hint files
+#[test]
+fn reopen_keyspaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let logs = store.create_keyspace("logs")?;
+    for id in 0..100 {
+        store.set(format!("key{}", id), format!("default{}", id))?;
+        logs.set(format!("key{}", id), format!("log{}", id))?;
+    }
+    logs.remove("key0".to_owned())?;
+    logs.set_with_ttl(
+        "expiring".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    drop(logs);
+    drop(store);
+
+    for compacted in [false, true] {
+        let store = KvStore::open(temp_dir.path())?;
+        let logs = store.keyspace("logs")?;
+        assert_eq!(store.list_keyspaces(), vec!["logs"]);
+        assert_eq!(logs.get("key0".to_owned())?, None);
+        for id in 1..100 {
+            assert_eq!(
+                store.get(format!("key{}", id))?,
+                Some(format!("default{}", id))
+            );
+            assert_eq!(logs.get(format!("key{}", id))?, Some(format!("log{}", id)));
+        }
+        assert_eq!(store.get("key0".to_owned())?, Some("default0".to_owned()));
+        if compacted {
+            thread::sleep(Duration::from_millis(300));
+            assert_eq!(logs.get("expiring".to_owned())?, None);
+        } else {
+            store.compact()?;
+        }
+    }
+    Ok(())
+}
+
+// A dropped keyspace should stay empty, also after compacting and reopening
+// the store and creating a keyspace with the same name
+#[test]
+fn drop_keyspace() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let old = store.create_keyspace("table")?;
+    let other = store.create_keyspace("other")?;
+    for id in 0..100 {
+        old.set(format!("key{}", id), format!("value{}", id))?;
+        other.set(format!("key{}", id), format!("value{}", id))?;
+    }
+    store.drop_keyspace("table")?;
+    assert_eq!(store.list_keyspaces(), vec!["other"]);
+    assert_eq!(old.get("key1".to_owned())?, None);
+    assert!(matches!(
+        old.set("key1".to_owned(), "value".to_owned()),
+        Err(KvsError::KeyspaceDropped)
+    ));
+    assert!(matches!(
+        store.drop_keyspace("table"),
+        Err(KvsError::KeyspaceNotFound(_))
+    ));
+
+    let new = store.create_keyspace("table")?;
+    assert_eq!(new.get("key1".to_owned())?, None);
+    new.set("key1".to_owned(), "new".to_owned())?;
+    assert_eq!(old.get("key1".to_owned())?, None);
+    drop((old, new, other));
+    drop(store);
+
+    for compact in [true, false] {
+        let store = KvStore::open(temp_dir.path())?;
+        let new = store.keyspace("table")?;
+        let other = store.keyspace("other")?;
+        assert_eq!(new.get("key1".to_owned())?, Some("new".to_owned()));
+        assert_eq!(new.get("key2".to_owned())?, None);
+        assert_eq!(other.get("key2".to_owned())?, Some("value2".to_owned()));
+        if compact {
+            store.compact()?;
+        }
+    }
+    Ok(())
+}
+
+// The dropped keyspaces should be compacted away
+#[test]
+fn compact_dropped_keyspace() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let table = store.create_keyspace("table")?;
+    for id in 0..1000 {
+        table.set(format!("key{}", id), "x".repeat(1000))?;
+    }
+    store.set("key".to_owned(), "value".to_owned())?;
+    store.compact()?;
+    let size = log_size(temp_dir.path());
+
+    store.drop_keyspace("table")?;
+    store.compact()?;
+    assert!(log_size(temp_dir.path()) < size / 10);
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// A checkpoint should hold the keyspaces of the store
+#[test]
+fn checkpoint_keyspaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let checkpoint_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store
+        .create_keyspace("table")?
+        .set("key".to_owned(), "value".to_owned())?;
+    store.checkpoint(checkpoint_dir.path())?;
+    store.create_keyspace("later")?;
+
+    let copy = KvStore::open(checkpoint_dir.path())?;
+    assert_eq!(copy.list_keyspaces(), vec!["table"]);
+    assert_eq!(
+        copy.keyspace("table")?.get("key".to_owned())?,
+        Some("value".to_owned())
+    );
+    Ok(())
+}
+
+// SledKvsEngine should map keyspaces to sled trees
+#[test]
+fn sled_keyspaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::new(sled::open(temp_dir.path())?)?;
+    let users = engine.create_keyspace("users")?;
+    engine.create_keyspace("orders")?;
+    assert_eq!(engine.list_keyspaces(), vec!["orders", "users"]);
+    assert!(matches!(
+        engine.create_keyspace("users"),
+        Err(KvsError::KeyspaceExists(_))
+    ));
+
+    engine.set("key".to_owned(), "default".to_owned())?;
+    users.set("key".to_owned(), "user".to_owned())?;
+    users.set_with_ttl(
+        "expiring".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    assert_eq!(engine.get("key".to_owned())?, Some("default".to_owned()));
+    assert_eq!(users.get("key".to_owned())?, Some("user".to_owned()));
+    assert_eq!(engine.get("expiring".to_owned())?, None);
+    assert_eq!(users.get("expiring".to_owned())?, Some("value".to_owned()));
+    // the reaper covers the expiry trees of the keyspaces
+    thread::sleep(Duration::from_millis(1500));
+    assert_eq!(
+        scan_keys(users.scan(.., ScanOptions::new())?)?,
+        vec!["key".to_owned()]
+    );
+
+    engine.drop_keyspace("orders")?;
+    assert_eq!(engine.list_keyspaces(), vec!["users"]);
+    assert!(matches!(
+        engine.keyspace("orders"),
+        Err(KvsError::KeyspaceNotFound(_))
+    ));
+    drop(users);
+    drop(engine);
+
+    let engine = SledKvsEngine::new(sled::open(temp_dir.path())?)?;
+    assert_eq!(engine.list_keyspaces(), vec!["users"]);
+    assert_eq!(
+        engine.keyspace("users")?.get("key".to_owned())?,
+        Some("user".to_owned())
+    );
+    Ok(())
+}
+
+// Values in sealed segments should be read through memory maps, also across
+// compactions deleting the mapped files
+#[test]
+fn mmap_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .mmap_reads(true)
+        .cache_size(0)
+        .compaction_trigger(CompactionTrigger::StaleBytes(16 * 1024))
+        .max_segment_size(4 * 1024);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter).repeat(50))?;
+        }
+        for key_id in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("{}", iter).repeat(50))
+            );
+        }
+    }
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("9".repeat(50)));
+    }
+    Ok(())
+}
+
 #[test]
 fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
@@ -158,6 +1968,277 @@ fn concurrent_set() -> Result<()> {
     Ok(())
 }
 
+// Every writer of a group commit should get the result of its own command
+#[test]
+fn concurrent_writers_get_own_results() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().durability(Durability::Always);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    let handles: Vec<_> = (0..100)
+        .map(|i| {
+            let store = store.clone();
+            thread::spawn(move || {
+                let key = format!("key{}", i);
+                store.set(key.clone(), "value".to_owned()).unwrap();
+                store.remove(key.clone()).unwrap();
+                match store.remove(key.clone()) {
+                    Err(KvsError::KeyNotFound(missing)) => assert_eq!(missing, key),
+                    res => panic!("expected KeyNotFound, got {:?}", res.map(|_| ())),
+                }
+                store.set(key, format!("value{}", i)).unwrap();
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    Ok(())
+}
+
+fn scan_keys(scan: kvs::Scan) -> Result<Vec<String>> {
+    scan.map(|entry| entry.map(|(key, _)| key)).collect()
+}
+
+fn check_scans<E: KvsEngine>(engine: E) -> Result<()> {
+    for key in &["a", "ab", "abc", "b", "ba", "c"] {
+        engine.set(key.to_string(), format!("{}-value", key))?;
+    }
+    engine.remove("ba".to_owned())?;
+
+    let all = ScanOptions::new();
+    assert_eq!(
+        engine
+            .scan("ab".to_owned().."c".to_owned(), all)?
+            .collect::<Result<Vec<_>>>()?,
+        vec![
+            ("ab".to_owned(), "ab-value".to_owned()),
+            ("abc".to_owned(), "abc-value".to_owned()),
+            ("b".to_owned(), "b-value".to_owned()),
+        ]
+    );
+    assert_eq!(
+        scan_keys(engine.scan(.., all)?)?,
+        ["a", "ab", "abc", "b", "c"]
+    );
+    assert_eq!(scan_keys(engine.scan(.., all.limit(2))?)?, ["a", "ab"]);
+    assert_eq!(
+        scan_keys(engine.scan(..="b".to_owned(), all.reverse(true))?)?,
+        ["b", "abc", "ab", "a"]
+    );
+    assert_eq!(
+        scan_keys(engine.scan(.., all.reverse(true).limit(2))?)?,
+        ["c", "b"]
+    );
+    assert_eq!(
+        scan_keys(engine.scan_prefix("ab".to_owned(), all)?)?,
+        ["ab", "abc"]
+    );
+    assert_eq!(
+        scan_keys(engine.scan_prefix("a".to_owned(), all.reverse(true))?)?,
+        ["abc", "ab", "a"]
+    );
+    assert!(scan_keys(engine.scan_prefix("d".to_owned(), all)?)?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn scan_kvs_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_scans(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn scan_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_scans(SledKvsEngine::new(sled::open(temp_dir.path())?)?)
+}
+
+// A scan keeps returning the values it started with while compactions delete
+// the log files they were read from.
+#[test]
+fn scan_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_trigger(CompactionTrigger::StaleBytes(1024))
+        .max_segment_size(1024)
+        .cache_size(0);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..100 {
+        store.set(format!("key{:03}", i), "old".to_owned())?;
+    }
+
+    let mut scan = store.scan_prefix("key".to_owned(), ScanOptions::new())?;
+    assert_eq!(
+        scan.next().transpose()?,
+        Some(("key000".to_owned(), "old".to_owned()))
+    );
+    for round in 0..20 {
+        for i in 0..100 {
+            store.set(format!("key{:03}", i), format!("new{}", round))?;
+        }
+    }
+    // wait for a compaction to delete the first generation of the old values
+    let first_log = temp_dir.path().join("1.log");
+    let start = Instant::now();
+    while first_log.exists() && start.elapsed() < Duration::from_secs(10) {
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(!first_log.exists());
+
+    let rest = scan.collect::<Result<Vec<_>>>()?;
+    assert_eq!(rest.len(), 99);
+    assert!(rest.iter().all(|(_, value)| value == "old"));
+    assert_eq!(store.get("key050".to_owned())?, Some("new19".to_owned()));
+    Ok(())
+}
+
+// A snapshot should keep seeing the values written before it was taken.
+#[test]
+fn snapshot_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("a".to_owned(), "a1".to_owned())?;
+    store.set("b".to_owned(), "b1".to_owned())?;
+    store.set("c".to_owned(), "c1".to_owned())?;
+
+    let snapshot = store.snapshot()?;
+    store.set("a".to_owned(), "a2".to_owned())?;
+    store.remove("b".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("c".to_owned(), "c2".to_owned());
+    batch.set("d".to_owned(), "d2".to_owned());
+    store.write_batch(batch)?;
+    let later = store.snapshot()?;
+    assert!(later.seq() > snapshot.seq());
+
+    assert_eq!(snapshot.get("a".to_owned())?, Some("a1".to_owned()));
+    assert_eq!(snapshot.get("b".to_owned())?, Some("b1".to_owned()));
+    assert_eq!(snapshot.get("d".to_owned())?, None);
+    let scan = snapshot.scan(.., ScanOptions::new())?;
+    assert_eq!(scan_keys(scan)?, ["a", "b", "c"]);
+    let scan = snapshot.scan_prefix("c".to_owned(), ScanOptions::new())?;
+    assert_eq!(
+        scan.collect::<Result<Vec<_>>>()?,
+        [("c".to_owned(), "c1".to_owned())]
+    );
+
+    assert_eq!(later.get("b".to_owned())?, None);
+    let scan = later.scan(.., ScanOptions::new())?;
+    assert_eq!(scan_keys(scan)?, ["a", "c", "d"]);
+    drop(snapshot);
+    drop(later);
+
+    assert_eq!(store.get("a".to_owned())?, Some("a2".to_owned()));
+    assert_eq!(store.get("b".to_owned())?, None);
+    let scan = store.scan(.., ScanOptions::new())?;
+    assert_eq!(scan_keys(scan)?, ["a", "c", "d"]);
+
+    // sequence numbers keep growing across reopens
+    let seq = store.snapshot()?.seq();
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("e".to_owned(), "e1".to_owned())?;
+    assert!(store.snapshot()?.seq() > seq);
+    Ok(())
+}
+
+// Compactions should keep the log files a snapshot reads, and compact them
+// once the snapshot is dropped.
+#[test]
+fn compact_with_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_trigger(CompactionTrigger::StaleBytes(16 * 1024))
+        .max_segment_size(4 * 1024);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "0".repeat(100))?;
+    }
+    let snapshot = store.snapshot()?;
+    for iter in 1..10 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter).repeat(100))?;
+        }
+    }
+    // wait for the background compactions
+    thread::sleep(Duration::from_millis(500));
+    for key_id in 0..100 {
+        assert_eq!(
+            snapshot.get(format!("key{}", key_id))?,
+            Some("0".repeat(100))
+        );
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("9".repeat(100)));
+    }
+    let scan = snapshot.scan(.., ScanOptions::new())?;
+    for entry in scan {
+        assert_eq!(entry?.1, "0".repeat(100));
+    }
+
+    drop(snapshot);
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "10".repeat(50))?;
+    }
+    thread::sleep(Duration::from_millis(500));
+    drop(store);
+    let total: u64 = log_sizes(temp_dir.path()).iter().sum();
+    assert!(total < 64 * 1024, "stale entries not compacted");
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("10".repeat(50)));
+    }
+    Ok(())
+}
+
+// Share one store between threads without cloning it, while compactions
+// replace the log files the readers have open.
+#[test]
+fn share_store_between_threads() -> Result<()> {
+    fn assert_sync<T: Sync>() {}
+    assert_sync::<KvStore>();
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_trigger(CompactionTrigger::StaleBytes(1024))
+        .max_segment_size(4096);
+    let store = Arc::new(KvStore::open_with(temp_dir.path(), options)?);
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let mut handles = Vec::new();
+    for thread_id in 0..8 {
+        let store = Arc::clone(&store);
+        handles.push(thread::spawn(move || {
+            for round in 0..20 {
+                for i in 0..100 {
+                    let key = format!("key{}", (i + thread_id) % 100);
+                    let value = store.get(key.clone()).unwrap().unwrap();
+                    assert!(value.starts_with("value"));
+                    if thread_id == 0 {
+                        store.set(key, format!("value{}", round)).unwrap();
+                    }
+                }
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some("value19".to_owned()));
+    }
+    Ok(())
+}
+
 #[test]
 fn concurrent_get() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");